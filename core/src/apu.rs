@@ -1,4 +1,7 @@
-use crate::memory::Memory;
+use crate::blip_buffer::{BlipBuffer, CLOCK_RATE};
+use crate::memory::{self, Memory};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use crate::wav;
 use std::cell::RefCell;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
@@ -13,12 +16,31 @@ const WAVEFORM: [usize; 4 * 8] = [
     0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0,
 ];
 
+// audio_buffer holds interleaved stereo frames: [left, right, left, right, ...].
+pub const AUDIO_CHANNELS: usize = 2;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+// charge factor of the high-pass filter capacitor per clock (DMG).
+const HPF_CHARGE_FACTOR: f32 = 0.999958;
+
 const DIVISOR: [usize; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
 
+// the longest periods of the frequency timers, in clocks.
+const MAX_FREQUENCY_TIMER: usize = 2048 * 4;
+const MAX_NOISE_FREQUENCY_TIMER: usize = 112 << 15;
+
 pub struct APU {
     pub memory: Rc<RefCell<Memory>>,
     pub audio_buffer: Vec<f32>,
-    pub sampling_timer: usize,
+    pub blip_buffers: [BlipBuffer; AUDIO_CHANNELS],
+    pub blip_clock: usize, // clocks since the start of the current audio frame
+    pub last_output: [f32; AUDIO_CHANNELS],
+    pub capacitor: [f32; AUDIO_CHANNELS],
+    pub channel_mask: u8, // bit n enables channel n + 1 in the mix
+    pub channel_buffers: [Vec<f32>; 4],
+    pub channel_taps: Vec<[f32; 4]>, // output of each channel every 4 clocks of the current audio frame
+    pub recording: Option<(u32, Vec<i16>)>, // (sample rate, samples interleaved like audio_buffer)
+    pub powered: bool,
     pub frame_sequencer_counter: usize,
     pub frame_sequencer_clock_counter: usize,
     pub frequency_timer_1: usize,
@@ -49,10 +71,21 @@ impl APU {
         APU {
             memory,
             audio_buffer: Vec::new(),
-            sampling_timer: 0,
+            blip_buffers: [
+                BlipBuffer::new(DEFAULT_SAMPLE_RATE),
+                BlipBuffer::new(DEFAULT_SAMPLE_RATE),
+            ],
+            blip_clock: 0,
+            last_output: [0.0; AUDIO_CHANNELS],
+            capacitor: [0.0; AUDIO_CHANNELS],
+            channel_mask: 0xf,
+            channel_buffers: Default::default(),
+            channel_taps: Vec::new(),
+            recording: None,
+            powered: false,
             frame_sequencer_counter: 0,
             frame_sequencer_clock_counter: 0,
-            frequency_timer_1: 0,
+            frequency_timer_1: MAX_FREQUENCY_TIMER,
             wave_duty_position_1: 0,
             period_timer_1: 0,
             length_timer_1: 0,
@@ -60,18 +93,18 @@ impl APU {
             shadow_frequency_1: 0,
             sweep_timer_1: 0,
             current_volume_1: 0,
-            frequency_timer_2: 0,
+            frequency_timer_2: MAX_FREQUENCY_TIMER,
             wave_duty_position_2: 0,
             period_timer_2: 0,
             length_timer_2: 0,
             current_volume_2: 0,
             sample_index_3: 0,
             length_timer_3: 0,
-            frequency_timer_3: 0,
+            frequency_timer_3: MAX_FREQUENCY_TIMER,
             period_timer_4: 0,
             length_timer_4: 0,
             current_volume_4: 0,
-            frequency_timer_4: 0,
+            frequency_timer_4: DIVISOR[0],
             lfsr: 0,
         }
     }
@@ -79,12 +112,12 @@ impl APU {
     fn calculate_frequency(&mut self) -> usize {
         let sweep_shift = (self.memory.borrow().nr10 & 0x7) as usize;
         let is_decrementing = (self.memory.borrow().nr10 & (1 << 3)) != 0;
-        let mut new_frequency = self.shadow_frequency_1 >> sweep_shift;
-        if is_decrementing {
-            new_frequency = self.shadow_frequency_1 - new_frequency;
+        let delta = self.shadow_frequency_1 >> sweep_shift;
+        let new_frequency = if is_decrementing {
+            self.shadow_frequency_1 - delta
         } else {
-            new_frequency = self.shadow_frequency_1 + new_frequency;
-        }
+            self.shadow_frequency_1 + delta
+        };
         /* overflow check */
         if new_frequency > 2047 {
             self.memory.borrow_mut().nr52 &= 0xfe;
@@ -93,16 +126,33 @@ impl APU {
     }
 
     pub fn tick(&mut self) {
+        let powered = self.memory.borrow().apu_powered();
+        if powered && !self.powered {
+            // powering on resets the frame sequencer and the wave position of each channel.
+            self.frame_sequencer_counter = 0;
+            self.frame_sequencer_clock_counter = 0;
+            self.wave_duty_position_1 = 0;
+            self.wave_duty_position_2 = 0;
+            self.sample_index_3 = 0;
+        }
+        self.powered = powered;
+        let writes = std::mem::take(&mut self.memory.borrow_mut().sound_register_writes);
+        for (address, previous, value) in writes {
+            self.apply_register_write(address, previous, value);
+        }
         if self.memory.borrow().nr14 & (1 << 7) != 0 {
             // restart channel 1
             self.memory.borrow_mut().nr14 ^= 1 << 7;
-            self.memory.borrow_mut().nr52 |= 1;
+            if memory::dac_enabled(self.memory.borrow().nr12) {
+                self.memory.borrow_mut().nr52 |= 1;
+            }
             let nr13 = self.memory.borrow().nr13 as usize;
             let nr14 = self.memory.borrow().nr14 as usize;
             let frequency_1 = nr13 | ((nr14 & 0x7) << 8);
             self.frequency_timer_1 = (2048 - frequency_1) * 4;
-            let initial_length_timer_1 = (self.memory.borrow().nr11 & 0x3f) as usize;
-            self.length_timer_1 = 64 - initial_length_timer_1;
+            if self.length_timer_1 == 0 {
+                self.length_timer_1 = self.reloaded_length(64, nr14 as u8);
+            }
             let initial_volume_1 = (self.memory.borrow().nr12 >> 4) as usize;
             self.current_volume_1 = initial_volume_1;
             let period_1 = (self.memory.borrow().nr12 & 0x7) as usize;
@@ -120,13 +170,16 @@ impl APU {
         if self.memory.borrow().nr24 & (1 << 7) != 0 {
             // restart channel 2
             self.memory.borrow_mut().nr24 ^= 1 << 7;
-            self.memory.borrow_mut().nr52 |= 1 << 1;
+            if memory::dac_enabled(self.memory.borrow().nr22) {
+                self.memory.borrow_mut().nr52 |= 1 << 1;
+            }
             let nr23 = self.memory.borrow().nr23 as usize;
             let nr24 = self.memory.borrow().nr24 as usize;
             let frequency_2 = nr23 | ((nr24 & 0x7) << 8);
             self.frequency_timer_2 = (2048 - frequency_2) * 4;
-            let initial_length_timer_2 = (self.memory.borrow().nr21 & 0x3f) as usize;
-            self.length_timer_2 = 64 - initial_length_timer_2;
+            if self.length_timer_2 == 0 {
+                self.length_timer_2 = self.reloaded_length(64, nr24 as u8);
+            }
             let initial_volume_2 = (self.memory.borrow().nr22 >> 4) as usize;
             self.current_volume_2 = initial_volume_2;
             let period_2 = (self.memory.borrow().nr22 & 0x7) as usize;
@@ -135,25 +188,32 @@ impl APU {
         if self.memory.borrow().nr34 & (1 << 7) != 0 {
             // restart channel 3
             self.memory.borrow_mut().nr34 ^= 1 << 7;
-            self.memory.borrow_mut().nr52 |= 1 << 2;
+            if self.memory.borrow().nr30 & (1 << 7) != 0 {
+                self.memory.borrow_mut().nr52 |= 1 << 2;
+            }
             let nr33 = self.memory.borrow().nr33 as usize;
             let nr34 = self.memory.borrow().nr34 as usize;
             let frequency_3 = nr33 | ((nr34 & 0x7) << 8);
             self.frequency_timer_3 = (2048 - frequency_3) * 4;
-            let initial_length_timer_3 = self.memory.borrow().nr31 as usize;
-            self.length_timer_3 = 256 - initial_length_timer_3;
+            if self.length_timer_3 == 0 {
+                self.length_timer_3 = self.reloaded_length(256, nr34 as u8);
+            }
             self.sample_index_3 = 0;
         }
         if self.memory.borrow().nr44 & (1 << 7) != 0 {
             // restart channel 4
             self.memory.borrow_mut().nr44 ^= 1 << 7;
-            self.memory.borrow_mut().nr52 |= 1 << 3;
+            if memory::dac_enabled(self.memory.borrow().nr42) {
+                self.memory.borrow_mut().nr52 |= 1 << 3;
+            }
             let nr43 = self.memory.borrow().nr43 as usize;
             let divisor = DIVISOR[nr43 & 0x7];
             let shift_amount = nr43 >> 4;
             self.frequency_timer_4 = divisor << shift_amount;
-            let initial_length_timer_4 = (self.memory.borrow().nr41 & 0x3f) as usize;
-            self.length_timer_4 = 64 - initial_length_timer_4;
+            if self.length_timer_4 == 0 {
+                let nr44 = self.memory.borrow().nr44;
+                self.length_timer_4 = self.reloaded_length(64, nr44);
+            }
             let initial_volume_4 = (self.memory.borrow().nr42 >> 4) as usize;
             self.current_volume_4 = initial_volume_4;
             let period_4 = (self.memory.borrow().nr42 & 0x7) as usize;
@@ -177,13 +237,19 @@ impl APU {
             self.frequency_timer_2 = (2048 - frequency_2) * 4;
         }
         self.frequency_timer_3 -= 1;
-        if self.frequency_timer_3 == 0 {
+        let wave_ram_fetched = self.frequency_timer_3 == 0;
+        if wave_ram_fetched {
             self.sample_index_3 = (self.sample_index_3 + 1) % 32;
             let nr33 = self.memory.borrow().nr33 as usize;
             let nr34 = self.memory.borrow().nr34 as usize;
             let frequency_3 = nr33 | ((nr34 & 0x7) << 8);
             self.frequency_timer_3 = (2048 - frequency_3) * 4;
         }
+        {
+            let mut memory = self.memory.borrow_mut();
+            memory.wave_ram_position = self.sample_index_3 / 2;
+            memory.wave_ram_fetched = wave_ram_fetched;
+        }
         self.frequency_timer_4 -= 1;
         if self.frequency_timer_4 == 0 {
             let divisor_code = (self.memory.borrow().nr43 & 0x7) as usize;
@@ -252,25 +318,25 @@ impl APU {
                 }
             }
             if self.frame_sequencer_clock_counter & 1 == 0 {
-                if self.memory.borrow().nr14 & (1 << 6) != 0 {
+                if self.memory.borrow().nr14 & (1 << 6) != 0 && self.length_timer_1 > 0 {
                     self.length_timer_1 -= 1;
                     if self.length_timer_1 == 0 {
                         self.memory.borrow_mut().nr52 &= 0xfe;
                     }
                 }
-                if self.memory.borrow().nr24 & (1 << 6) != 0 {
+                if self.memory.borrow().nr24 & (1 << 6) != 0 && self.length_timer_2 > 0 {
                     self.length_timer_2 -= 1;
                     if self.length_timer_2 == 0 {
                         self.memory.borrow_mut().nr52 &= 0xfd;
                     }
                 }
-                if self.memory.borrow().nr34 & (1 << 6) != 0 {
+                if self.memory.borrow().nr34 & (1 << 6) != 0 && self.length_timer_3 > 0 {
                     self.length_timer_3 -= 1;
                     if self.length_timer_3 == 0 {
                         self.memory.borrow_mut().nr52 &= 0xfb;
                     }
                 }
-                if self.memory.borrow().nr44 & (1 << 6) != 0 {
+                if self.memory.borrow().nr44 & (1 << 6) != 0 && self.length_timer_4 > 0 {
                     self.length_timer_4 -= 1;
                     if self.length_timer_4 == 0 {
                         self.memory.borrow_mut().nr52 &= 0xf7;
@@ -304,53 +370,674 @@ impl APU {
             }
             self.frame_sequencer_clock_counter = (self.frame_sequencer_clock_counter + 1) & 0x7;
         }
-        self.sampling_timer += 1;
-        if self.sampling_timer == 87 {
-            self.sampling_timer = 0;
-            let mut amplitude = 0.0;
-            if self.memory.borrow().nr52 & 1 != 0 {
-                let wave_duty_pattern = (self.memory.borrow().nr11 >> 6) as usize;
-                let dac_input = WAVEFORM[wave_duty_pattern * 8 + self.wave_duty_position_1]
-                    * self.current_volume_1;
-                amplitude += (dac_input as f32) / 7.5 - 1.0;
-            }
-            if self.memory.borrow().nr52 & (1 << 1) != 0 {
-                let wave_duty_pattern = (self.memory.borrow().nr21 >> 6) as usize;
-                let dac_input = WAVEFORM[wave_duty_pattern * 8 + self.wave_duty_position_2]
-                    * self.current_volume_2;
-                amplitude += (dac_input as f32) / 7.5 - 1.0;
-            }
-            if self.memory.borrow().nr52 & (1 << 2) != 0 {
-                let sample_index = self.sample_index_3;
-                let mut dac_input = self
-                    .memory
-                    .borrow()
-                    .get_byte((0xff30 + sample_index / 2) as u16);
-                if sample_index & 1 == 0 {
-                    dac_input = (dac_input >> 4) & 0xf;
-                } else {
-                    dac_input = dac_input & 0xf;
-                }
-                let volume = (self.memory.borrow().nr32 >> 5) & 0x3;
-                if volume == 0 {
-                    dac_input >>= 4;
-                } else if volume == 2 {
-                    dac_input >>= 1;
-                } else if volume == 3 {
-                    dac_input >>= 2;
+        self.blip_clock += 1;
+        // the output level can only change on M-cycle boundaries (for all practical purposes),
+        // so it is checked every 4 clocks.
+        if self.blip_clock & 0x3 == 0 {
+            let memory = self.memory.borrow();
+            let nr52 = memory.nr52;
+            let mut outputs = [0.0; 4];
+            let wave_duty_pattern = (memory.nr11 >> 6) as usize;
+            let dac_input =
+                WAVEFORM[wave_duty_pattern * 8 + self.wave_duty_position_1] * self.current_volume_1;
+            outputs[0] = dac_output(memory::dac_enabled(memory.nr12), nr52 & 1 != 0, dac_input);
+            let wave_duty_pattern = (memory.nr21 >> 6) as usize;
+            let dac_input =
+                WAVEFORM[wave_duty_pattern * 8 + self.wave_duty_position_2] * self.current_volume_2;
+            outputs[1] = dac_output(
+                memory::dac_enabled(memory.nr22),
+                nr52 & (1 << 1) != 0,
+                dac_input,
+            );
+            let sample_index = self.sample_index_3;
+            let mut dac_input = memory.wave_ram[sample_index / 2];
+            if sample_index & 1 == 0 {
+                dac_input = (dac_input >> 4) & 0xf;
+            } else {
+                dac_input &= 0xf;
+            }
+            let volume = (memory.nr32 >> 5) & 0x3;
+            if volume == 0 {
+                dac_input >>= 4;
+            } else if volume == 2 {
+                dac_input >>= 1;
+            } else if volume == 3 {
+                dac_input >>= 2;
+            }
+            outputs[2] = dac_output(
+                memory.nr30 & (1 << 7) != 0,
+                nr52 & (1 << 2) != 0,
+                dac_input as usize,
+            );
+            let dac_input = ((self.lfsr ^ 0x7fff) & 0x1) * self.current_volume_4;
+            outputs[3] = dac_output(
+                memory::dac_enabled(memory.nr42),
+                nr52 & (1 << 3) != 0,
+                dac_input,
+            );
+            drop(memory);
+            self.channel_taps.push(outputs);
+            let (left, right) = self.mix(outputs);
+            for (i, level) in [left, right].into_iter().enumerate() {
+                if level != self.last_output[i] {
+                    self.blip_buffers[i].add_delta(self.blip_clock, level - self.last_output[i]);
+                    self.last_output[i] = level;
                 }
-                amplitude += (dac_input as f32) / 7.5 - 1.0;
             }
-            if self.memory.borrow().nr52 & (1 << 3) != 0 {
-                let dac_input = ((self.lfsr ^ 0x7fff) & 0x1) * self.current_volume_4;
-                amplitude += (dac_input as f32) / 7.5 - 1.0;
+        }
+    }
+
+    // effects of the writes to NRx1, NRx2 and NRx4 on the length counters and the envelopes.
+    // see https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Obscure_Behavior
+    fn apply_register_write(&mut self, address: u16, previous: u8, value: u8) {
+        let nr52 = self.memory.borrow().nr52;
+        match address {
+            0xff11 => self.length_timer_1 = 64 - (value & 0x3f) as usize,
+            0xff16 => self.length_timer_2 = 64 - (value & 0x3f) as usize,
+            0xff1b => self.length_timer_3 = 256 - value as usize,
+            0xff20 => self.length_timer_4 = 64 - (value & 0x3f) as usize,
+            0xff12 if nr52 & 1 != 0 => {
+                self.current_volume_1 = zombie_volume(self.current_volume_1, previous, value);
+            }
+            0xff17 if nr52 & (1 << 1) != 0 => {
+                self.current_volume_2 = zombie_volume(self.current_volume_2, previous, value);
+            }
+            0xff21 if nr52 & (1 << 3) != 0 => {
+                self.current_volume_4 = zombie_volume(self.current_volume_4, previous, value);
             }
-            amplitude /= 4.0;
-            self.audio_buffer.push(amplitude);
+            0xff14 => {
+                self.length_timer_1 = self.enable_length(0, self.length_timer_1, previous, value);
+            }
+            0xff19 => {
+                self.length_timer_2 = self.enable_length(1, self.length_timer_2, previous, value);
+            }
+            0xff1e => {
+                self.length_timer_3 = self.enable_length(2, self.length_timer_3, previous, value);
+            }
+            0xff23 => {
+                self.length_timer_4 = self.enable_length(3, self.length_timer_4, previous, value);
+            }
+            _ => {}
+        }
+    }
+
+    // enabling the length counter when the next frame sequencer step does not clock it
+    // clocks it once more. if that expires it and the channel is not triggered, it turns off.
+    fn enable_length(&self, channel: usize, length_timer: usize, previous: u8, nrx4: u8) -> usize {
+        let enabled = previous & (1 << 6) == 0 && nrx4 & (1 << 6) != 0;
+        if !enabled || !self.extra_length_clock() || length_timer == 0 {
+            return length_timer;
+        }
+        if length_timer == 1 && nrx4 & (1 << 7) == 0 {
+            self.memory.borrow_mut().nr52 &= !(1 << channel);
         }
+        length_timer - 1
+    }
+
+    // the next step of the frame sequencer does not clock the length counters.
+    fn extra_length_clock(&self) -> bool {
+        self.frame_sequencer_clock_counter & 1 != 0
+    }
+
+    // triggering a channel whose length counter has expired reloads it with the full length,
+    // minus the extra clock if the length counter is enabled.
+    fn reloaded_length(&self, full_length: usize, nrx4: u8) -> usize {
+        if nrx4 & (1 << 6) != 0 && self.extra_length_clock() {
+            full_length - 1
+        } else {
+            full_length
+        }
+    }
+
+    // NR51 routes each channel to the left and/or right output,
+    // then NR50 scales each side by (volume + 1) / 8.
+    fn mix(&self, outputs: [f32; 4]) -> (f32, f32) {
+        let nr50 = self.memory.borrow().nr50;
+        let nr51 = self.memory.borrow().nr51;
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            if self.channel_mask & (1 << i) == 0 {
+                continue; // muted
+            }
+            if nr51 & (1 << (i + 4)) != 0 {
+                left += output;
+            }
+            if nr51 & (1 << i) != 0 {
+                right += output;
+            }
+        }
+        let left_volume = (((nr50 >> 4) & 0x7) + 1) as f32 / 8.0;
+        let right_volume = ((nr50 & 0x7) + 1) as f32 / 8.0;
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+
+    // record the output of each channel, before muting and mixing, once per output sample
+    // that end_frame produced, so the channel buffers stay as long as audio_buffer.
+    fn tap_channels(&mut self, samples: usize) {
+        let taps = self.channel_taps.len();
+        for i in 0..samples {
+            for (c, buffer) in self.channel_buffers.iter_mut().enumerate() {
+                // a frame shorter than 4 clocks has no taps. repeat the previous sample.
+                let output = match taps {
+                    0 => buffer.last().copied().unwrap_or(0.0),
+                    _ => self.channel_taps[i * taps / samples][c],
+                };
+                buffer.push(output);
+            }
+        }
+        self.channel_taps.clear();
+    }
+
+    // while STOP halts the system clock, the channels are frozen at their current output.
+    // the audio frame still advances, so end_frame keeps producing samples of the held level.
+    pub fn tick_stopped(&mut self) {
+        self.blip_clock += 1;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        // the clocks of the current frame are resampled at the old rate first,
+        // and the new buffers continue from the current level to avoid a click.
+        self.end_frame();
+        for (i, blip_buffer) in self.blip_buffers.iter_mut().enumerate() {
+            *blip_buffer = BlipBuffer::new(sample_rate);
+            blip_buffer.set_level(self.last_output[i]);
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.blip_buffers[0].sample_rate()
+    }
+
+    // resample everything generated since the last call and append it to audio_buffer.
+    pub fn end_frame(&mut self) {
+        for blip_buffer in self.blip_buffers.iter_mut() {
+            blip_buffer.end_frame(self.blip_clock);
+        }
+        self.blip_clock = 0;
+        let left = self.blip_buffers[0].read_samples();
+        let right = self.blip_buffers[1].read_samples();
+        self.tap_channels(left.len());
+        let charge_factor = self.charge_factor();
+        for (l, r) in left.into_iter().zip(right) {
+            let l = self.high_pass(0, l, charge_factor);
+            let r = self.high_pass(1, r, charge_factor);
+            self.audio_buffer.push(l);
+            self.audio_buffer.push(r);
+            if let Some((_, recording)) = self.recording.as_mut() {
+                recording.push(wav::to_pcm16(l));
+                recording.push(wav::to_pcm16(r));
+            }
+        }
+    }
+
+    // the high-pass filter runs once per output sample rather than once per clock,
+    // so the capacitor charges by the amount of all the clocks in between.
+    fn charge_factor(&self) -> f32 {
+        HPF_CHARGE_FACTOR.powf(CLOCK_RATE as f32 / self.sample_rate() as f32)
+    }
+
+    // the capacitor on each output removes the DC offset of the DACs.
+    // see https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Obscure_Behavior
+    fn high_pass(&mut self, channel: usize, input: f32, charge_factor: f32) -> f32 {
+        let output = input - self.capacitor[channel];
+        self.capacitor[channel] = input - output * charge_factor;
+        output
     }
 
     pub fn clear_audio_buffer(&mut self) {
-        self.audio_buffer.clear()
+        self.audio_buffer.clear();
+        for buffer in self.channel_buffers.iter_mut() {
+            buffer.clear();
+        }
+    }
+}
+
+impl Snapshot for APU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.blip_clock);
+        writer.write_usize(self.frame_sequencer_counter);
+        writer.write_usize(self.frame_sequencer_clock_counter);
+        writer.write_usize(self.frequency_timer_1);
+        writer.write_usize(self.wave_duty_position_1);
+        writer.write_usize(self.period_timer_1);
+        writer.write_usize(self.length_timer_1);
+        writer.write_bool(self.sweep_enable_1);
+        writer.write_usize(self.shadow_frequency_1);
+        writer.write_usize(self.sweep_timer_1);
+        writer.write_usize(self.current_volume_1);
+        writer.write_usize(self.frequency_timer_2);
+        writer.write_usize(self.wave_duty_position_2);
+        writer.write_usize(self.period_timer_2);
+        writer.write_usize(self.length_timer_2);
+        writer.write_usize(self.current_volume_2);
+        writer.write_usize(self.sample_index_3);
+        writer.write_usize(self.length_timer_3);
+        writer.write_usize(self.frequency_timer_3);
+        writer.write_usize(self.period_timer_4);
+        writer.write_usize(self.length_timer_4);
+        writer.write_usize(self.current_volume_4);
+        writer.write_usize(self.frequency_timer_4);
+        writer.write_usize(self.lfsr);
+        writer.write_bool(self.powered);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        // the resampler state is not saved. restart it from silence.
+        self.blip_clock = read_counter(reader, CLOCK_RATE as usize, "audio frame clock")?;
+        for blip_buffer in self.blip_buffers.iter_mut() {
+            blip_buffer.clear();
+        }
+        self.last_output = [0.0; AUDIO_CHANNELS];
+        self.capacitor = [0.0; AUDIO_CHANNELS];
+        self.channel_taps.clear();
+        self.frame_sequencer_counter = read_counter(reader, 8191, "frame sequencer")?;
+        self.frame_sequencer_clock_counter = read_counter(reader, 7, "frame sequencer step")?;
+        self.frequency_timer_1 = read_timer(reader, MAX_FREQUENCY_TIMER)?;
+        self.wave_duty_position_1 = read_counter(reader, 7, "duty position")?;
+        self.period_timer_1 = read_counter(reader, 7, "envelope timer")?;
+        self.length_timer_1 = read_counter(reader, 64, "length timer")?;
+        self.sweep_enable_1 = reader.read_bool()?;
+        self.shadow_frequency_1 = read_counter(reader, 2047, "sweep frequency")?;
+        self.sweep_timer_1 = read_counter(reader, 8, "sweep timer")?;
+        self.current_volume_1 = read_counter(reader, 15, "volume")?;
+        self.frequency_timer_2 = read_timer(reader, MAX_FREQUENCY_TIMER)?;
+        self.wave_duty_position_2 = read_counter(reader, 7, "duty position")?;
+        self.period_timer_2 = read_counter(reader, 7, "envelope timer")?;
+        self.length_timer_2 = read_counter(reader, 64, "length timer")?;
+        self.current_volume_2 = read_counter(reader, 15, "volume")?;
+        self.sample_index_3 = read_counter(reader, 31, "wave position")?;
+        self.length_timer_3 = read_counter(reader, 256, "length timer")?;
+        self.frequency_timer_3 = read_timer(reader, MAX_FREQUENCY_TIMER)?;
+        self.period_timer_4 = read_counter(reader, 7, "envelope timer")?;
+        self.length_timer_4 = read_counter(reader, 64, "length timer")?;
+        self.current_volume_4 = read_counter(reader, 15, "volume")?;
+        self.frequency_timer_4 = read_timer(reader, MAX_NOISE_FREQUENCY_TIMER)?;
+        self.lfsr = read_counter(reader, 0x7fff, "LFSR")?;
+        self.powered = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+fn read_counter(
+    reader: &mut StateReader,
+    max: usize,
+    name: &'static str,
+) -> Result<usize, StateError> {
+    let value = reader.read_usize()?;
+    if value > max {
+        return Err(StateError::InvalidData(name));
+    }
+    Ok(value)
+}
+
+// frequency timers are decremented before they are checked, so they are never 0.
+fn read_timer(reader: &mut StateReader, max: usize) -> Result<usize, StateError> {
+    match reader.read_usize()? {
+        0 => Err(StateError::InvalidData("frequency timer")),
+        value if value > max => Err(StateError::InvalidData("frequency timer")),
+        value => Ok(value),
+    }
+}
+
+// a DAC converts a digital value 0-15 to an analog level between -1.0 and 1.0.
+// a disabled DAC outputs nothing, while an enabled DAC of a disabled channel outputs the level of 0.
+// writing NRx2 while the channel is on changes its volume ("zombie mode").
+// some games use it to change the volume without retriggering the channel.
+fn zombie_volume(volume: usize, previous: u8, nrx2: u8) -> usize {
+    let mut volume = volume as u8;
+    if previous & 0x7 == 0 {
+        volume += 1;
+    } else if previous & (1 << 3) == 0 {
+        volume += 2;
+    }
+    if (previous ^ nrx2) & (1 << 3) != 0 {
+        volume = 16u8.wrapping_sub(volume);
+    }
+    (volume & 0xf) as usize
+}
+
+fn dac_output(dac_enabled: bool, channel_enabled: bool, dac_input: usize) -> f32 {
+    if !dac_enabled {
+        0.0
+    } else if channel_enabled {
+        (dac_input as f32) / 7.5 - 1.0
+    } else {
+        -1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::StateReader;
+
+    type Corrupt = fn(&mut APU);
+    type Volume = fn(&APU) -> usize;
+
+    fn reload(apu: &APU) -> Result<(), StateError> {
+        let mut writer = StateWriter::new();
+        apu.save_state(&mut writer);
+        let state = writer.finish();
+        let mut reader = StateReader::new(&state)?;
+        APU::new(Rc::clone(&apu.memory)).load_state(&mut reader)
+    }
+
+    #[test]
+    fn load_state_rejects_out_of_range_values() {
+        let cases: [(&str, Corrupt); 6] = [
+            ("wave position", |apu| apu.sample_index_3 = 32),
+            ("duty position", |apu| apu.wave_duty_position_1 = 8),
+            ("frequency timer", |apu| apu.frequency_timer_2 = 0),
+            ("noise timer", |apu| apu.frequency_timer_4 = usize::MAX),
+            ("volume", |apu| apu.current_volume_4 = 16),
+            ("frame sequencer", |apu| {
+                apu.frame_sequencer_clock_counter = 8
+            }),
+        ];
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        assert!(reload(&APU::new(Rc::clone(&memory))).is_ok());
+        for (name, corrupt) in cases.iter() {
+            let mut apu = APU::new(Rc::clone(&memory));
+            corrupt(&mut apu);
+            assert!(
+                matches!(reload(&apu), Err(StateError::InvalidData(_))),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn set_sample_rate_flushes_the_frame() {
+        // (old sample rate, new sample rate, clocks in the current frame)
+        let cases = [
+            (48000, 44100, 1000),
+            (44100, 48000, 70224),
+            (8000, 192000, 0),
+        ];
+        for &(old_rate, new_rate, clocks) in cases.iter() {
+            let mut apu = APU::new(Rc::new(RefCell::new(Memory::new())));
+            apu.set_sample_rate(old_rate);
+            for blip_buffer in apu.blip_buffers.iter_mut() {
+                blip_buffer.add_delta(0, 0.5);
+            }
+            apu.last_output = [0.5; AUDIO_CHANNELS];
+            apu.blip_clock = clocks;
+            apu.set_sample_rate(new_rate);
+            let samples = clocks * old_rate as usize / CLOCK_RATE as usize;
+            assert_eq!(
+                apu.audio_buffer.len(),
+                samples * AUDIO_CHANNELS,
+                "{} Hz",
+                old_rate
+            );
+            assert_eq!(apu.sample_rate(), new_rate);
+            assert_eq!(apu.last_output, [0.5; AUDIO_CHANNELS]);
+            // the new buffers start from the current level instead of 0.
+            for blip_buffer in apu.blip_buffers.iter_mut() {
+                blip_buffer.end_frame(1000);
+                assert!(blip_buffer.read_samples().iter().all(|&x| x == 0.5));
+            }
+        }
+    }
+
+    #[test]
+    fn channel_buffers_match_audio_buffer() {
+        // (sample rate, clocks per frame)
+        let cases = [
+            (48000, 70224),
+            (44100, 70224),
+            (8000, 70224),
+            (192000, 35112),
+            (44100, 1001),
+            (22050, 3),
+        ];
+        for &(sample_rate, clocks) in cases.iter() {
+            let mut apu = APU::new(Rc::new(RefCell::new(Memory::new())));
+            apu.set_sample_rate(sample_rate);
+            for frame in 0..10 {
+                for _ in 0..clocks {
+                    apu.tick();
+                }
+                apu.end_frame();
+                let samples = apu.audio_buffer.len() / AUDIO_CHANNELS;
+                for buffer in apu.channel_buffers.iter() {
+                    assert_eq!(
+                        buffer.len(),
+                        samples,
+                        "{} Hz, {} clocks, frame {}",
+                        sample_rate,
+                        clocks,
+                        frame
+                    );
+                }
+                apu.clear_audio_buffer();
+            }
+        }
+    }
+
+    // an APU powered on, with the frame sequencer at its first step.
+    fn powered_apu(cgb_mode: bool) -> APU {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        memory.borrow_mut().cgb_mode = cgb_mode;
+        memory.borrow_mut().set_byte(0xff26, 0x80);
+        let mut apu = APU::new(memory);
+        apu.tick();
+        apu
+    }
+
+    #[test]
+    fn length_counter_extra_clocks() {
+        // (next frame sequencer step, NR11, first NR14 write, second NR14 write,
+        //  length counter, channel on)
+        let cases = [
+            // enabling the length counter before a step that does not clock it clocks it once.
+            (1, 0x3f, 0x80, 0x40, 0, false),
+            (0, 0x3f, 0x80, 0x40, 1, true),
+            (1, 0x3e, 0x80, 0x40, 1, true),
+            // enabling it again without disabling it first does not clock it.
+            (1, 0x3e, 0xc0, 0x40, 1, true),
+            // the trigger reloads the expired length counter and clocks it again.
+            (1, 0x3f, 0x80, 0xc0, 63, true),
+            (0, 0x3f, 0x80, 0xc0, 1, true),
+            // the trigger does not reload a length counter that has not expired.
+            (1, 0x00, 0x80, 0xc0, 63, true),
+            (0, 0x00, 0x80, 0xc0, 64, true),
+        ];
+        for &(step, nr11, first, second, length, channel_on) in cases.iter() {
+            let mut apu = powered_apu(false);
+            apu.frame_sequencer_clock_counter = step;
+            for &(address, value) in [
+                (0xff11, nr11),
+                (0xff12, 0xf0),
+                (0xff14, first),
+                (0xff14, second),
+            ]
+            .iter()
+            {
+                apu.memory.borrow_mut().set_byte(address, value);
+                apu.tick();
+            }
+            let case = (step, nr11, first, second);
+            assert_eq!(apu.length_timer_1, length, "{:x?}", case);
+            assert_eq!(apu.memory.borrow().nr52 & 1 != 0, channel_on, "{:x?}", case);
+        }
+    }
+
+    #[test]
+    fn zombie_mode_envelope_writes() {
+        // (NRx2 when triggered, NRx2 written while playing, volume after the write)
+        let cases = [
+            (0xa0, 0xa0, 11), // period 0: +1
+            (0xa1, 0xa1, 12), // decreasing: +2
+            (0xa9, 0xa9, 10), // increasing: unchanged
+            (0xa0, 0xa8, 5),  // the direction changes: 16 - (10 + 1)
+            (0xa9, 0xa1, 6),  // 16 - 10
+            (0xf0, 0xf0, 0),  // only the low 4 bits are kept
+            (0xf1, 0xf9, 15), // 16 - (15 + 2)
+        ];
+        // (NRx2, NRx4, volume)
+        let channels: [(u16, u16, Volume); 3] = [
+            (0xff12, 0xff14, |apu| apu.current_volume_1),
+            (0xff17, 0xff19, |apu| apu.current_volume_2),
+            (0xff21, 0xff23, |apu| apu.current_volume_4),
+        ];
+        for &(nrx2, nrx4, volume) in channels.iter() {
+            for &(initial, written, expected) in cases.iter() {
+                let mut apu = powered_apu(false);
+                apu.memory.borrow_mut().set_byte(nrx2, initial);
+                apu.memory.borrow_mut().set_byte(nrx4, 0x80);
+                apu.tick();
+                apu.memory.borrow_mut().set_byte(nrx2, written);
+                apu.tick();
+                assert_eq!(
+                    volume(&apu),
+                    expected,
+                    "{:#06x}: {:#04x}, {:#04x}",
+                    nrx2,
+                    initial,
+                    written
+                );
+            }
+            // the volume of a channel that is off does not change.
+            let mut apu = powered_apu(false);
+            apu.memory.borrow_mut().set_byte(nrx2, 0xa0);
+            apu.tick();
+            apu.memory.borrow_mut().set_byte(nrx2, 0xa8);
+            apu.tick();
+            assert_eq!(volume(&apu), 0, "{:#06x}", nrx2);
+        }
+    }
+
+    #[test]
+    fn wave_ram_access_while_playing() {
+        // (CGB, accessible between the fetches of channel 3)
+        let cases = [(false, false), (true, true)];
+        for &(cgb_mode, accessible) in cases.iter() {
+            let mut apu = powered_apu(cgb_mode);
+            for i in 0..16 {
+                apu.memory.borrow_mut().set_byte(0xff30 + i, i as u8 * 0x11);
+            }
+            assert_eq!(apu.memory.borrow().get_byte(0xff35), 0x55);
+            apu.memory.borrow_mut().set_byte(0xff1a, 0x80);
+            apu.memory.borrow_mut().set_byte(0xff1d, 0xfe); // a fetch every 8 clocks
+            apu.memory.borrow_mut().set_byte(0xff1e, 0x87);
+            while apu.sample_index_3 != 8 || !apu.memory.borrow().wave_ram_fetched {
+                apu.tick();
+            }
+            // in the clock of a fetch, any address reaches the byte being played.
+            assert_eq!(
+                apu.memory.borrow().get_byte(0xff35),
+                0x44,
+                "CGB {}",
+                cgb_mode
+            );
+            apu.memory.borrow_mut().set_byte(0xff3f, 0xab);
+            assert_eq!(apu.memory.borrow().wave_ram[4], 0xab, "CGB {}", cgb_mode);
+
+            apu.tick();
+            let expected = if accessible { 0xab } else { 0xff };
+            assert_eq!(
+                apu.memory.borrow().get_byte(0xff35),
+                expected,
+                "CGB {}",
+                cgb_mode
+            );
+            apu.memory.borrow_mut().set_byte(0xff30, 0xcd);
+            let expected = if accessible { 0xcd } else { 0xab };
+            assert_eq!(
+                apu.memory.borrow().wave_ram[4],
+                expected,
+                "CGB {}",
+                cgb_mode
+            );
+            assert_eq!(apu.memory.borrow().wave_ram[0], 0x00, "CGB {}", cgb_mode);
+
+            // once the channel is off, the whole wave RAM is accessible again.
+            apu.memory.borrow_mut().set_byte(0xff1a, 0x00);
+            assert_eq!(
+                apu.memory.borrow().get_byte(0xff35),
+                0x55,
+                "CGB {}",
+                cgb_mode
+            );
+        }
+    }
+
+    #[test]
+    fn dac_output_levels() {
+        // (DAC enabled, channel enabled, DAC input, expected output)
+        let cases = [
+            (false, true, 15, 0.0),
+            (false, false, 0, 0.0),
+            (true, false, 15, -1.0),
+            (true, true, 0, -1.0),
+            (true, true, 15, 1.0),
+        ];
+        for &(dac_enabled, channel_enabled, dac_input, expected) in cases.iter() {
+            assert_eq!(
+                dac_output(dac_enabled, channel_enabled, dac_input),
+                expected,
+                "dac {}, channel {}, input {}",
+                dac_enabled,
+                channel_enabled,
+                dac_input
+            );
+        }
+    }
+
+    #[test]
+    fn high_pass_decay_does_not_depend_on_sample_rate() {
+        // a constant input decays by HPF_CHARGE_FACTOR per clock: 0.999958 ^ 4194.304 after 1 ms.
+        let expected = 0.8385;
+        for sample_rate in [8000, 32000, 44100, 48000, 96000] {
+            let mut apu = APU::new(Rc::new(RefCell::new(Memory::new())));
+            apu.set_sample_rate(sample_rate);
+            let charge_factor = apu.charge_factor();
+            // the first sample passes through, then each one decays by the charge factor.
+            let mut output = 0.0;
+            for _ in 0..=sample_rate / 1000 {
+                output = apu.high_pass(0, 1.0, charge_factor);
+            }
+            assert!(
+                (output - expected).abs() < 1e-3,
+                "rate {}: {}",
+                sample_rate,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn power_off_clears_registers_and_channels() {
+        // (NRx2 address, value enabling the DAC, NRx4 address, NRx2 read after power-off)
+        let cases = [
+            (0xff12, 0xf0, 0xff14, 0x00),
+            (0xff17, 0xf0, 0xff19, 0x00),
+            (0xff1a, 0x80, 0xff1e, 0x7f),
+            (0xff21, 0xf0, 0xff23, 0x00),
+        ];
+        for (channel, &(dac, value, trigger, expected)) in cases.iter().enumerate() {
+            let memory = Rc::new(RefCell::new(Memory::new()));
+            let mut apu = APU::new(Rc::clone(&memory));
+            memory.borrow_mut().set_byte(0xff26, 0x80);
+            memory.borrow_mut().set_byte(dac, value);
+            memory.borrow_mut().set_byte(trigger, 0x80);
+            apu.tick();
+            assert_eq!(memory.borrow().get_byte(0xff26), 0xf0 | (1 << channel));
+            apu.frame_sequencer_clock_counter = 5;
+
+            memory.borrow_mut().set_byte(0xff26, 0x00);
+            apu.tick();
+            assert_eq!(memory.borrow().get_byte(0xff26), 0x70);
+            assert_eq!(memory.borrow().get_byte(dac), expected, "{:#06x}", dac);
+            assert!(!apu.powered);
+
+            // powering on again restarts the frame sequencer.
+            memory.borrow_mut().set_byte(0xff26, 0x80);
+            apu.tick();
+            assert_eq!(apu.frame_sequencer_clock_counter, 0);
+        }
     }
 }
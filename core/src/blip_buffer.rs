@@ -0,0 +1,209 @@
+use std::f64::consts::PI;
+
+// Band-limited step synthesis.
+// the APU reports every change of its output level as a delta at a clock time,
+// and each delta is turned into a band-limited step at the output sample rate.
+// this avoids the aliasing of point sampling square waves.
+// see http://www.slack.net/~ant/bl-synth/
+
+pub const CLOCK_RATE: f64 = 4194304.0;
+
+const PHASES: usize = 32; // sub-sample resolution of step positions
+const KERNEL_WIDTH: usize = 16; // output samples touched by each step
+const CUTOFF: f64 = 0.45; // low-pass cutoff relative to the output sample rate
+
+pub struct BlipBuffer {
+    sample_rate: u32,
+    factor: f64, // output samples per clock
+    offset: f64, // position of the start of the current frame, in output samples
+    deltas: Vec<f64>,
+    integrator: f64,
+    available: usize,
+    kernel: Vec<[f64; KERNEL_WIDTH]>,
+}
+
+impl BlipBuffer {
+    pub fn new(sample_rate: u32) -> BlipBuffer {
+        BlipBuffer {
+            sample_rate,
+            factor: sample_rate as f64 / CLOCK_RATE,
+            offset: 0.0,
+            deltas: vec![0.0; KERNEL_WIDTH],
+            integrator: 0.0,
+            available: 0,
+            kernel: build_kernel(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn clear(&mut self) {
+        self.offset = 0.0;
+        self.deltas.clear();
+        self.deltas.resize(KERNEL_WIDTH, 0.0);
+        self.integrator = 0.0;
+        self.available = 0;
+    }
+
+    // start the output at `level` instead of 0.
+    pub fn set_level(&mut self, level: f32) {
+        self.integrator = level as f64;
+    }
+
+    // add a step of `delta` at `clock` clocks after the start of the current frame.
+    // the output is delayed by KERNEL_WIDTH / 2 samples.
+    pub fn add_delta(&mut self, clock: usize, delta: f32) {
+        let pos = self.available as f64 + self.offset + clock as f64 * self.factor;
+        let i = pos as usize;
+        let phase = (((pos - i as f64) * PHASES as f64) as usize).min(PHASES - 1);
+        if self.deltas.len() < i + KERNEL_WIDTH {
+            self.deltas.resize(i + KERNEL_WIDTH, 0.0);
+        }
+        for (j, k) in self.kernel[phase].iter().enumerate() {
+            self.deltas[i + j] += delta as f64 * k;
+        }
+    }
+
+    // end the current frame after `clocks` clocks.
+    // the samples before that point become available to read_samples.
+    pub fn end_frame(&mut self, clocks: usize) {
+        let end = self.offset + clocks as f64 * self.factor;
+        let n = end as usize;
+        self.available += n;
+        self.offset = end - n as f64;
+        let len = self.available + KERNEL_WIDTH;
+        if self.deltas.len() < len {
+            self.deltas.resize(len, 0.0);
+        }
+    }
+
+    pub fn read_samples(&mut self) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(self.available);
+        for delta in self.deltas.drain(0..self.available) {
+            self.integrator += delta;
+            samples.push(self.integrator as f32);
+        }
+        self.available = 0;
+        samples
+    }
+}
+
+// kernel[phase][j] is the impulse response of the low-pass filter for a step
+// located `phase / PHASES` samples after the sample at index KERNEL_WIDTH / 2 - 1.
+fn build_kernel() -> Vec<[f64; KERNEL_WIDTH]> {
+    let half = (KERNEL_WIDTH / 2) as f64;
+    (0..PHASES)
+        .map(|phase| {
+            let frac = phase as f64 / PHASES as f64;
+            let mut taps = [0.0; KERNEL_WIDTH];
+            for (j, tap) in taps.iter_mut().enumerate() {
+                let t = j as f64 - (half - 1.0) - frac;
+                let x = 2.0 * CUTOFF * t;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                // Blackman window over [-half, half].
+                let w = (t + half) / (2.0 * half);
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                *tap = sinc * window;
+            }
+            // each step must add exactly `delta` in total.
+            let sum: f64 = taps.iter().sum();
+            for tap in taps.iter_mut() {
+                *tap /= sum;
+            }
+            taps
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCKS_PER_FRAME: usize = 70224;
+
+    #[test]
+    fn samples_per_frame() {
+        // (sample rate, samples in the first frame, samples in 60 frames)
+        let cases = [(44100, 738, 44301), (48000, 803, 48218), (8000, 133, 8036)];
+        for &(sample_rate, first_frame, total) in cases.iter() {
+            let mut blip_buffer = BlipBuffer::new(sample_rate);
+            blip_buffer.end_frame(CLOCKS_PER_FRAME);
+            let mut n = blip_buffer.read_samples().len();
+            assert_eq!(n, first_frame, "{} Hz", sample_rate);
+            for _ in 1..60 {
+                blip_buffer.end_frame(CLOCKS_PER_FRAME);
+                let len = blip_buffer.read_samples().len();
+                assert!(
+                    len == first_frame || len == first_frame + 1,
+                    "{} Hz",
+                    sample_rate
+                );
+                n += len;
+            }
+            assert_eq!(n, total, "{} Hz", sample_rate);
+        }
+    }
+
+    #[test]
+    fn step_settles_to_its_amplitude() {
+        // (sample rate, clock of the step, amplitude)
+        let cases = [
+            (44100, 0, 1.0),
+            (48000, 100, -0.5),
+            (48000, 1000, 0.25),
+            (8000, 5000, 2.0),
+        ];
+        for &(sample_rate, clock, amplitude) in cases.iter() {
+            let mut blip_buffer = BlipBuffer::new(sample_rate);
+            blip_buffer.add_delta(clock, amplitude);
+            blip_buffer.end_frame(CLOCKS_PER_FRAME);
+            let samples = blip_buffer.read_samples();
+            let step = (clock as f64 * (sample_rate as f64 / CLOCK_RATE)) as usize;
+            assert!(
+                samples[..step].iter().all(|&x| x == 0.0),
+                "{} Hz",
+                sample_rate
+            );
+            for &x in samples[step + KERNEL_WIDTH..].iter() {
+                assert!((x - amplitude).abs() < 1e-6, "{} Hz: {}", sample_rate, x);
+            }
+        }
+    }
+
+    #[test]
+    fn end_frame_carries_fractional_clocks() {
+        // a step in a frame split into short frames lands where it does in one long frame.
+        // (sample rate, clocks per short frame, short frames, clock of the step)
+        let cases = [
+            (44100, 100, 100, 9999),
+            (48000, 70, 30, 1234),
+            (8000, 1, 5000, 2500),
+        ];
+        for &(sample_rate, clocks, frames, clock) in cases.iter() {
+            let mut whole = BlipBuffer::new(sample_rate);
+            whole.add_delta(clock, 1.0);
+            whole.end_frame(clocks * frames);
+            let expected = whole.read_samples();
+
+            let mut split = BlipBuffer::new(sample_rate);
+            let mut samples = vec![];
+            for frame in 0..frames {
+                if clock / clocks == frame {
+                    split.add_delta(clock % clocks, 1.0);
+                }
+                split.end_frame(clocks);
+                samples.extend(split.read_samples());
+            }
+            assert_eq!(samples.len(), expected.len(), "{} Hz", sample_rate);
+            for (i, (&x, &y)) in samples.iter().zip(expected.iter()).enumerate() {
+                assert!((x - y).abs() < 1e-4, "{} Hz, sample {}", sample_rate, i);
+            }
+        }
+    }
+}
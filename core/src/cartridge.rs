@@ -0,0 +1,248 @@
+use crate::mapper;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+// see https://gbdev.io/pandocs/The_Cartridge_Header.html
+const HEADER_END: usize = 0x150;
+// MBC5 can address up to 512 banks of 16 KiB.
+const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    TooSmall(usize),
+    TooLarge(usize),
+    UnsupportedCartridgeType(u8),
+    HeaderChecksumMismatch { expected: u8, actual: u8 },
+    GlobalChecksumMismatch { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(n) => {
+                write!(f, "ROM is too small to contain a header ({} bytes)", n)
+            }
+            CartridgeError::TooLarge(n) => {
+                write!(f, "ROM is too large ({} bytes, at most 8 MiB)", n)
+            }
+            CartridgeError::UnsupportedCartridgeType(cart_type) => {
+                write!(f, "unsupported cartridge type 0x{:02x}", cart_type)
+            }
+            CartridgeError::HeaderChecksumMismatch { expected, actual } => write!(
+                f,
+                "header checksum mismatch (expected 0x{:02x}, got 0x{:02x})",
+                expected, actual
+            ),
+            CartridgeError::GlobalChecksumMismatch { expected, actual } => write!(
+                f,
+                "global checksum mismatch (expected 0x{:04x}, got 0x{:04x})",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct CartridgeInfo {
+    #[wasm_bindgen(readonly, getter_with_clone)]
+    pub title: String,
+    // 0x80: CGB enhanced (backwards compatible), 0xC0: CGB only, otherwise DMG only.
+    #[wasm_bindgen(readonly)]
+    pub cgb_flag: u8,
+    #[wasm_bindgen(readonly)]
+    pub sgb_support: bool,
+    // the new licensee code (two ASCII characters) if the old one is 0x33,
+    // the old licensee code in hex otherwise.
+    #[wasm_bindgen(readonly, getter_with_clone)]
+    pub licensee_code: String,
+    #[wasm_bindgen(readonly)]
+    pub cartridge_type: u8,
+    #[wasm_bindgen(readonly)]
+    pub rom_size: usize,
+    #[wasm_bindgen(readonly)]
+    pub ram_size: usize,
+    #[wasm_bindgen(readonly)]
+    pub destination_code: u8,
+    #[wasm_bindgen(readonly)]
+    pub version: u8,
+    #[wasm_bindgen(readonly)]
+    pub header_checksum: u8,
+    #[wasm_bindgen(readonly)]
+    pub global_checksum: u16,
+}
+
+impl CartridgeInfo {
+    pub fn parse(rom: &[u8]) -> Result<CartridgeInfo, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(CartridgeError::TooLarge(rom.len()));
+        }
+
+        let cgb_flag = rom[0x143];
+        // on CGB cartridges the end of the title area is used for the manufacturer code
+        // and the CGB flag.
+        let title_end = if cgb_flag & 0x80 != 0 { 0x13f } else { 0x144 };
+        let title = rom[0x134..title_end]
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| if c.is_ascii_graphic() { c as char } else { ' ' })
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let old_licensee_code = rom[0x14b];
+        let licensee_code = if old_licensee_code == 0x33 {
+            String::from_utf8_lossy(&rom[0x144..0x146]).into_owned()
+        } else {
+            format!("{:02X}", old_licensee_code)
+        };
+
+        let cartridge_type = rom[0x147];
+        if !mapper::is_supported(cartridge_type) {
+            return Err(CartridgeError::UnsupportedCartridgeType(cartridge_type));
+        }
+
+        let header_checksum = rom[0x14d];
+        let actual = rom[0x134..=0x14c]
+            .iter()
+            .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+        if actual != header_checksum {
+            return Err(CartridgeError::HeaderChecksumMismatch {
+                expected: header_checksum,
+                actual,
+            });
+        }
+
+        let global_checksum = ((rom[0x14e] as u16) << 8) | rom[0x14f] as u16;
+        let actual = self::global_checksum(rom);
+        if actual != global_checksum {
+            return Err(CartridgeError::GlobalChecksumMismatch {
+                expected: global_checksum,
+                actual,
+            });
+        }
+
+        Ok(CartridgeInfo {
+            title,
+            cgb_flag,
+            sgb_support: rom[0x146] == 0x03,
+            licensee_code,
+            cartridge_type,
+            rom_size: (32 * 1024) << (rom[0x148] & 0xf),
+            ram_size: mapper::ram_size(rom[0x149]),
+            destination_code: rom[0x14a],
+            version: rom[0x14c],
+            header_checksum,
+            global_checksum,
+        })
+    }
+}
+
+// sum of all bytes except the global checksum itself.
+fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x14e && i != 0x14f)
+        .fold(0u16, |x, (_, &b)| x.wrapping_add(b as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 32 KiB ROM with valid header and global checksums.
+    fn rom_with_header(title: &str, cgb_flag: u8, cartridge_type: u8, rom: u8, ram: u8) -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        data[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        data[0x143] = cgb_flag;
+        data[0x147] = cartridge_type;
+        data[0x148] = rom;
+        data[0x149] = ram;
+        data[0x14d] = data[0x134..=0x14c]
+            .iter()
+            .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+        let sum = global_checksum(&data);
+        data[0x14e] = (sum >> 8) as u8;
+        data[0x14f] = sum as u8;
+        data
+    }
+
+    #[test]
+    fn parse_title_and_cgb_flag() {
+        // (title area, CGB flag, expected title)
+        let cases = [
+            ("TETRIS", 0x00, "TETRIS"),
+            ("POKEMON RED\0\0\0\0", 0x00, "POKEMON RED"),
+            ("RTC\x01TEST  ", 0x00, "RTC TEST"),
+            // the last 4 bytes of the title area are the manufacturer code on CGB cartridges.
+            ("ZELDA DX\0\0\0AZ6E", 0x80, "ZELDA DX"),
+            ("POKEMON_SLVAAXE", 0xc0, "POKEMON_SLV"),
+        ];
+        for &(title, cgb_flag, expected) in cases.iter() {
+            let info = CartridgeInfo::parse(&rom_with_header(title, cgb_flag, 0, 0, 0)).unwrap();
+            assert_eq!(info.title, expected);
+            assert_eq!(info.cgb_flag, cgb_flag, "{}", expected);
+        }
+    }
+
+    #[test]
+    fn parse_cartridge_type_and_sizes() {
+        // (cartridge type, ROM size code, RAM size code, ROM size, RAM size)
+        let cases = [
+            (0x00, 0x00, 0x00, 0x8000, 0),
+            (0x03, 0x02, 0x02, 0x20000, 0x2000), // MBC1+RAM+BATTERY
+            (0x06, 0x01, 0x00, 0x10000, 0),      // MBC2+BATTERY
+            (0x10, 0x06, 0x04, 0x200000, 0x20000), // MBC3+TIMER+RAM+BATTERY
+            (0x13, 0x05, 0x03, 0x100000, 0x8000), // MBC3+RAM+BATTERY
+            (0x1b, 0x08, 0x05, 0x800000, 0x10000), // MBC5+RAM+BATTERY
+        ];
+        for &(cartridge_type, rom, ram, rom_size, ram_size) in cases.iter() {
+            let data = rom_with_header("TEST", 0x00, cartridge_type, rom, ram);
+            let info = CartridgeInfo::parse(&data).unwrap();
+            assert_eq!(info.cartridge_type, cartridge_type);
+            assert_eq!(info.rom_size, rom_size, "type {:#04x}", cartridge_type);
+            assert_eq!(info.ram_size, ram_size, "type {:#04x}", cartridge_type);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_invalid_headers() {
+        let valid = rom_with_header("TEST", 0x00, 0x01, 0x00, 0x00);
+        let mut bad_header_checksum = valid.clone();
+        bad_header_checksum[0x14d] ^= 1;
+        let mut unsupported = valid.clone();
+        unsupported[0x147] = 0x22; // MBC7
+        let mut oversized = valid.clone();
+        oversized.resize(MAX_ROM_SIZE + 0x4000, 0);
+        let mut bad_global_checksum = valid.clone();
+        bad_global_checksum[0x14e] = 0x12;
+        // (ROM, accepted)
+        let cases = [
+            (valid[..0x14f].to_vec(), false),
+            (oversized, false),
+            (bad_header_checksum, false),
+            (unsupported, false),
+            (bad_global_checksum, false),
+            (valid, true),
+        ];
+        for (i, (data, accepted)) in cases.iter().enumerate() {
+            assert_eq!(CartridgeInfo::parse(data).is_ok(), *accepted, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn global_checksum_skips_its_own_bytes() {
+        let mut data = rom_with_header("TEST", 0x00, 0x00, 0x00, 0x00);
+        let sum = global_checksum(&data);
+        data[0x14e] = 0xab;
+        data[0x14f] = 0xcd;
+        assert_eq!(global_checksum(&data), sum);
+        data[0x150] = 0x01;
+        assert_eq!(global_checksum(&data), sum.wrapping_add(1));
+    }
+}
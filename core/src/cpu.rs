@@ -1,5 +1,6 @@
 use crate::instruction::{self, Inst, InstKind, JumpCond, Operand16, Operand8};
 use crate::memory::Memory;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
@@ -24,6 +25,8 @@ pub struct CPU {
     pub sub_inst_table: Vec<Inst>,
     pub is_halt: bool,
     pub is_halt_bug_occured: bool,
+    pub is_locked_up: bool,
+    pub is_stopped: bool,
 }
 
 impl CPU {
@@ -42,212 +45,57 @@ impl CPU {
             sub_inst_table,
             is_halt: false,
             is_halt_bug_occured: false,
+            is_locked_up: false,
+            is_stopped: false,
         }
     }
 
-    pub fn decode(&mut self) -> Inst {
+    // returns None for the illegal opcodes
+    // (0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC and 0xFD).
+    pub fn decode(&mut self) -> Option<Inst> {
         let opcode = self.memory.borrow().get_byte(self.registers.pc);
         if self.is_halt_bug_occured {
             self.is_halt_bug_occured = false;
         } else {
-            self.registers.pc += 1;
+            self.registers.pc = self.registers.pc.wrapping_add(1);
         }
-        let inst = if opcode == 0xcb {
-            let opcode = self.memory.borrow().get_byte(self.registers.pc);
-            self.registers.pc += 1;
-            self.sub_inst_table[opcode as usize]
+        self.main_inst_table[opcode as usize]
+    }
+
+    // immediate operands follow the opcode and are fetched one byte per M-cycle
+    // into `tmp`, low byte first.
+    fn fetch_operand(&mut self, inst: InstKind) {
+        let index = self.clock_counter / 4 - 1;
+        if index == 0 || index > inst.operand_bytes() {
+            return;
+        }
+        let n = self.memory.borrow().get_byte(self.registers.pc) as usize;
+        self.registers.pc = self.registers.pc.wrapping_add(1);
+        if index == 1 {
+            self.tmp = n;
         } else {
-            match self.main_inst_table[opcode as usize] {
-                Some(inst) => inst,
-                None => panic!("unknown opcode {}", opcode),
-            }
-        };
-        match inst.kind {
-            InstKind::Load8(dst, Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::Load8(dst, Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::Load8(dst, Operand8::Address(Operand16::Imm(_))) => {
-                let n = self.memory.borrow().get_word(self.registers.pc);
-                self.registers.pc += 2;
-                Inst {
-                    kind: InstKind::Load8(dst, Operand8::Address(Operand16::Imm(n))),
-                    ..inst
-                }
-            }
-            InstKind::Load8(Operand8::Address(Operand16::Imm(_)), src) => {
-                let n = self.memory.borrow().get_word(self.registers.pc);
-                self.registers.pc += 2;
-                Inst {
-                    kind: InstKind::Load8(Operand8::Address(Operand16::Imm(n)), src),
-                    ..inst
-                }
-            }
-            InstKind::Load8(dst, Operand8::IOPortImm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::Load8(dst, Operand8::IOPortImm(n)),
-                    ..inst
-                }
-            }
-            InstKind::Load8(Operand8::IOPortImm(_), src) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::Load8(Operand8::IOPortImm(n), src),
-                    ..inst
-                }
-            }
-            InstKind::Load16(dst, Operand16::Imm(_)) => {
-                let n = self.memory.borrow().get_word(self.registers.pc);
-                self.registers.pc += 2;
-                Inst {
-                    kind: InstKind::Load16(dst, Operand16::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::Load16(Operand16::AddressImm(_), src) => {
-                let n = self.memory.borrow().get_word(self.registers.pc);
-                self.registers.pc += 2;
-                Inst {
-                    kind: InstKind::Load16(Operand16::AddressImm(n), src),
-                    ..inst
-                }
-            }
-            InstKind::Add8(Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::Add8(Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::AddCarry8(Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::AddCarry8(Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::Sub8(Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::Sub8(Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::SubCarry8(Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::SubCarry8(Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::And8(Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::And8(Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::Xor8(Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::Xor8(Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::Or8(Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::Or8(Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::Compare8(Operand8::Imm(_)) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc);
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::Compare8(Operand8::Imm(n)),
-                    ..inst
-                }
-            }
-            InstKind::AddSP(_) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc) as i8;
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::AddSP(n),
-                    ..inst
-                }
-            }
-            InstKind::AddAndLoadHL(_) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc) as i8;
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::AddAndLoadHL(n),
-                    ..inst
-                }
-            }
-            InstKind::JumpImm(_) => {
-                let n = self.memory.borrow().get_word(self.registers.pc);
-                self.registers.pc += 2;
-                Inst {
-                    kind: InstKind::JumpImm(n),
-                    ..inst
-                }
-            }
-            InstKind::JumpCondImm(cond, _) => {
-                let n = self.memory.borrow().get_word(self.registers.pc);
-                self.registers.pc += 2;
-                Inst {
-                    kind: InstKind::JumpCondImm(cond, n),
-                    ..inst
-                }
-            }
-            InstKind::JumpRel(_) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc) as i8;
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::JumpRel(n),
-                    ..inst
-                }
-            }
-            InstKind::JumpCondRel(cond, _) => {
-                let n = self.memory.borrow().get_byte(self.registers.pc) as i8;
-                self.registers.pc += 1;
-                Inst {
-                    kind: InstKind::JumpCondRel(cond, n),
-                    ..inst
-                }
-            }
-            InstKind::CallImm(_) => {
-                let n = self.memory.borrow().get_word(self.registers.pc);
-                self.registers.pc += 2;
-                Inst {
-                    kind: InstKind::CallImm(n),
-                    ..inst
-                }
-            }
-            InstKind::CallCondImm(cond, _) => {
-                let n = self.memory.borrow().get_word(self.registers.pc);
-                self.registers.pc += 2;
-                Inst {
-                    kind: InstKind::CallCondImm(cond, n),
-                    ..inst
-                }
-            }
-            _ => inst,
+            self.tmp |= n << 8;
+        }
+    }
+
+    fn push_byte(&mut self, value: u8) {
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.memory.borrow_mut().set_byte(self.registers.sp, value);
+    }
+
+    fn pop_byte(&mut self) -> u8 {
+        let value = self.memory.borrow().get_byte(self.registers.sp);
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        value
+    }
+
+    fn check_cond(&self, cond: JumpCond) -> bool {
+        let flags = Flags::from(self.registers.f);
+        match cond {
+            JumpCond::NZ => !flags.z,
+            JumpCond::Z => flags.z,
+            JumpCond::NC => !flags.c,
+            JumpCond::C => flags.c,
         }
     }
 
@@ -260,13 +108,13 @@ impl CPU {
             Operand8::RegE => self.registers.e,
             Operand8::RegH => self.registers.h,
             Operand8::RegL => self.registers.l,
-            Operand8::Imm(n) => n,
+            Operand8::Imm => self.tmp as u8,
             Operand8::Address(op16) => {
                 let addr = self.get16(op16);
                 self.memory.borrow().get_byte(addr)
             }
-            Operand8::IOPortImm(n) => {
-                let addr = 0xff00 + n as u16;
+            Operand8::IOPortImm => {
+                let addr = 0xff00 + self.tmp as u16;
                 self.memory.borrow().get_byte(addr)
             }
             Operand8::IOPortC => {
@@ -283,7 +131,7 @@ impl CPU {
             Operand16::RegHL => self.registers.get_hl(),
             Operand16::RegSP => self.registers.sp,
             Operand16::RegAF => self.registers.get_af(),
-            Operand16::Imm(value) => value,
+            Operand16::Imm => self.tmp as u16,
             _ => unreachable!(),
         }
     }
@@ -301,8 +149,8 @@ impl CPU {
                 let addr = self.get16(op16);
                 self.memory.borrow_mut().set_byte(addr, value);
             }
-            Operand8::IOPortImm(n) => {
-                let addr = 0xff00 + n as u16;
+            Operand8::IOPortImm => {
+                let addr = 0xff00 + self.tmp as u16;
                 self.memory.borrow_mut().set_byte(addr, value);
             }
             Operand8::IOPortC => {
@@ -320,9 +168,6 @@ impl CPU {
             Operand16::RegHL => self.registers.set_hl(value),
             Operand16::RegSP => self.registers.sp = value,
             Operand16::RegAF => self.registers.set_af(value),
-            Operand16::AddressImm(addr) => {
-                self.memory.borrow_mut().set_word(addr, value);
-            }
             _ => unreachable!(),
         }
     }
@@ -344,7 +189,7 @@ impl CPU {
                 let hl = self.registers.get_hl();
                 let value = self.registers.a;
                 self.memory.borrow_mut().set_byte(hl, value);
-                self.registers.set_hl(hl + 1);
+                self.registers.set_hl(hl.wrapping_add(1));
             }
             InstKind::LoadIncToA => {
                 if self.clock_counter < self.clocks_to_finish {
@@ -353,7 +198,7 @@ impl CPU {
                 let hl = self.registers.get_hl();
                 let value = self.memory.borrow().get_byte(hl);
                 self.registers.a = value;
-                self.registers.set_hl(hl + 1);
+                self.registers.set_hl(hl.wrapping_add(1));
             }
             InstKind::LoadDecFromA => {
                 if self.clock_counter < self.clocks_to_finish {
@@ -362,7 +207,7 @@ impl CPU {
                 let hl = self.registers.get_hl();
                 let value = self.registers.a;
                 self.memory.borrow_mut().set_byte(hl, value);
-                self.registers.set_hl(hl - 1);
+                self.registers.set_hl(hl.wrapping_sub(1));
             }
             InstKind::LoadDecToA => {
                 if self.clock_counter < self.clocks_to_finish {
@@ -371,7 +216,17 @@ impl CPU {
                 let hl = self.registers.get_hl();
                 let value = self.memory.borrow().get_byte(hl);
                 self.registers.a = value;
-                self.registers.set_hl(hl - 1);
+                self.registers.set_hl(hl.wrapping_sub(1));
+            }
+            InstKind::Load16(Operand16::AddressImm, src) => {
+                // `LD (nn), SP` writes the low byte then the high byte.
+                let addr = self.tmp as u16;
+                let [lo, hi] = self.get16(src).to_le_bytes();
+                if self.clock_counter == 16 {
+                    self.memory.borrow_mut().set_byte(addr, lo);
+                } else if self.clock_counter == 20 {
+                    self.memory.borrow_mut().set_byte(addr.wrapping_add(1), hi);
+                }
             }
             InstKind::Load16(dst, src) => {
                 if self.clock_counter < self.clocks_to_finish {
@@ -380,14 +235,14 @@ impl CPU {
                 let value = self.get16(src);
                 self.set16(dst, value);
             }
-            InstKind::AddAndLoadHL(n) => {
+            InstKind::AddAndLoadHL => {
                 // 00hc
                 if self.clock_counter < self.clocks_to_finish {
                     return;
                 }
                 let mut flags = Flags::from(self.registers.f);
                 let a = self.registers.sp;
-                let b = n as u16; // sign extension
+                let b = self.tmp as u8 as i8 as u16; // sign extension
                 let value = a.wrapping_add(b);
                 let carry = ((a & 0xff) + (b & 0xff)) > 0xff;
                 let half_carry = ((a & 0xf) + (b & 0xf)) > 0xf;
@@ -399,20 +254,21 @@ impl CPU {
                 self.registers.f = flags.into();
             }
             InstKind::Push(op) => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
+                // the high byte is pushed first, after an internal M-cycle.
+                let [lo, hi] = self.get16(op).to_le_bytes();
+                if self.clock_counter == 12 {
+                    self.push_byte(hi);
+                } else if self.clock_counter == 16 {
+                    self.push_byte(lo);
                 }
-                let value = self.get16(op);
-                self.registers.sp -= 2;
-                self.memory.borrow_mut().set_word(self.registers.sp, value);
             }
             InstKind::Pop(op) => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
+                if self.clock_counter == 8 {
+                    self.tmp = self.pop_byte() as usize;
+                } else if self.clock_counter == 12 {
+                    let hi = self.pop_byte() as u16;
+                    self.set16(op, (hi << 8) | self.tmp as u16);
                 }
-                let value = self.memory.borrow_mut().get_word(self.registers.sp);
-                self.registers.sp += 2;
-                self.set16(op, value);
             }
             InstKind::Add8(op) => {
                 // z0hc
@@ -466,14 +322,14 @@ impl CPU {
                 flags.c = carry;
                 self.registers.f = flags.into();
             }
-            InstKind::AddSP(n) => {
+            InstKind::AddSP => {
                 // 00hc
                 if self.clock_counter < self.clocks_to_finish {
                     return;
                 }
                 let mut flags = Flags::from(self.registers.f);
                 let a = self.registers.sp;
-                let b = n as u16; // sign extension
+                let b = self.tmp as u8 as i8 as u16; // sign extension
                 let value = a.wrapping_add(b);
                 let carry = ((a & 0xff) + (b & 0xff)) > 0xff;
                 let half_carry = ((a & 0xf) + (b & 0xf)) > 0xf;
@@ -1130,7 +986,24 @@ impl CPU {
                     self.is_halt = true;
                 }
             }
-            InstKind::Stop => {}
+            InstKind::Stop => {
+                if self.clock_counter < self.clocks_to_finish {
+                    return;
+                }
+                let mut memory = self.memory.borrow_mut();
+                if memory.cgb_mode && memory.speed_switch_armed {
+                    // CGB speed switch. DIV is reset and the CPU is stopped while the clock settles.
+                    memory.double_speed = !memory.double_speed;
+                    memory.speed_switch_armed = false;
+                    memory.system_counter = 0;
+                    memory.cpu_stall_clocks += 8200;
+                } else if memory.joypad & 0xf == 0xf {
+                    // the system clock is stopped until a selected joypad line goes low.
+                    // with a button already held, STOP does nothing.
+                    memory.system_counter = 0;
+                    self.is_stopped = true;
+                }
+            }
             InstKind::DisableInterrupt => {
                 if self.clock_counter < self.clocks_to_finish {
                     return;
@@ -1138,11 +1011,11 @@ impl CPU {
                 self.memory.borrow_mut().interrupt_master_enable = false;
             }
             InstKind::EnableInterrupt => {} // the effect of EI is delayed by one instruction
-            InstKind::JumpImm(addr) => {
+            InstKind::JumpImm => {
                 if self.clock_counter < self.clocks_to_finish {
                     return;
                 }
-                self.registers.pc = addr;
+                self.registers.pc = self.tmp as u16;
             }
             InstKind::JumpHL => {
                 if self.clock_counter < self.clocks_to_finish {
@@ -1151,126 +1024,146 @@ impl CPU {
                 let addr = self.registers.get_hl();
                 self.registers.pc = addr;
             }
-            InstKind::JumpCondImm(cond, addr) => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
-                }
-                let flags = Flags::from(self.registers.f);
-                let jump_cond = match cond {
-                    JumpCond::NZ => !flags.z,
-                    JumpCond::Z => flags.z,
-                    JumpCond::NC => !flags.c,
-                    JumpCond::C => flags.c,
-                };
-                if jump_cond {
-                    // if jump condition is met, convert it to unconditional jump
-                    self.current_inst = Some(InstKind::JumpImm(addr));
-                    self.clocks_to_finish = 4;
-                    self.clock_counter = 0;
+            InstKind::JumpCondImm(cond) => {
+                // if jump condition is met, it takes one more M-cycle to jump.
+                if self.clock_counter == 12 && self.check_cond(cond) {
+                    self.clocks_to_finish = 16;
+                } else if self.clock_counter == 16 {
+                    self.registers.pc = self.tmp as u16;
                 }
             }
-            InstKind::JumpRel(offset) => {
+            InstKind::JumpRel => {
                 if self.clock_counter < self.clocks_to_finish {
                     return;
                 }
-                let addr = self.registers.pc.wrapping_add(offset as u16);
-                self.registers.pc = addr;
+                let offset = self.tmp as u8 as i8;
+                self.registers.pc = self.registers.pc.wrapping_add(offset as u16);
             }
-            InstKind::JumpCondRel(cond, offset) => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
-                }
-                let flags = Flags::from(self.registers.f);
-                let jump_cond = match cond {
-                    JumpCond::NZ => !flags.z,
-                    JumpCond::Z => flags.z,
-                    JumpCond::NC => !flags.c,
-                    JumpCond::C => flags.c,
-                };
-                if jump_cond {
-                    // if jump condition is met, convert it to unconditional jump
-                    self.current_inst = Some(InstKind::JumpRel(offset));
-                    self.clocks_to_finish = 4;
-                    self.clock_counter = 0;
+            InstKind::JumpCondRel(cond) => {
+                if self.clock_counter == 8 && self.check_cond(cond) {
+                    self.clocks_to_finish = 12;
+                } else if self.clock_counter == 12 {
+                    let offset = self.tmp as u8 as i8;
+                    self.registers.pc = self.registers.pc.wrapping_add(offset as u16);
                 }
             }
-            InstKind::CallImm(addr) => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
+            InstKind::CallImm => {
+                // the return address is pushed after an internal M-cycle.
+                let [lo, hi] = self.registers.pc.to_le_bytes();
+                if self.clock_counter == 20 {
+                    self.push_byte(hi);
+                } else if self.clock_counter == 24 {
+                    self.push_byte(lo);
+                    self.registers.pc = self.tmp as u16;
                 }
-                self.registers.sp -= 2;
-                self.memory
-                    .borrow_mut()
-                    .set_word(self.registers.sp, self.registers.pc);
-                self.registers.pc = addr;
             }
-            InstKind::CallCondImm(cond, addr) => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
-                }
-                let flags = Flags::from(self.registers.f);
-                let jump_cond = match cond {
-                    JumpCond::NZ => !flags.z,
-                    JumpCond::Z => flags.z,
-                    JumpCond::NC => !flags.c,
-                    JumpCond::C => flags.c,
-                };
-                if jump_cond {
-                    // if jump condition is met, convert it to unconditional call
-                    self.current_inst = Some(InstKind::CallImm(addr));
-                    self.clocks_to_finish = 12;
-                    self.clock_counter = 0;
+            InstKind::CallCondImm(cond) => {
+                let [lo, hi] = self.registers.pc.to_le_bytes();
+                if self.clock_counter == 12 && self.check_cond(cond) {
+                    self.clocks_to_finish = 24;
+                } else if self.clock_counter == 20 {
+                    self.push_byte(hi);
+                } else if self.clock_counter == 24 {
+                    self.push_byte(lo);
+                    self.registers.pc = self.tmp as u16;
                 }
             }
             InstKind::Return => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
+                // the return address is popped, then PC is set in an internal M-cycle.
+                if self.clock_counter == 8 {
+                    self.tmp = self.pop_byte() as usize;
+                } else if self.clock_counter == 12 {
+                    self.tmp |= (self.pop_byte() as usize) << 8;
+                } else if self.clock_counter == 16 {
+                    self.registers.pc = self.tmp as u16;
                 }
-                let addr = self.memory.borrow().get_word(self.registers.sp);
-                self.registers.pc = addr;
-                self.registers.sp += 2;
             }
             InstKind::ReturnCond(cond) => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
-                }
-                let flags = Flags::from(self.registers.f);
-                let jump_cond = match cond {
-                    JumpCond::NZ => !flags.z,
-                    JumpCond::Z => flags.z,
-                    JumpCond::NC => !flags.c,
-                    JumpCond::C => flags.c,
-                };
-                if jump_cond {
-                    // if jump condition is met, convert it to unconditional return
-                    self.current_inst = Some(InstKind::Return);
-                    self.clocks_to_finish = 12;
-                    self.clock_counter = 0;
+                // the condition is checked in an internal M-cycle before popping.
+                if self.clock_counter == 8 && self.check_cond(cond) {
+                    self.clocks_to_finish = 20;
+                } else if self.clock_counter == 12 {
+                    self.tmp = self.pop_byte() as usize;
+                } else if self.clock_counter == 16 {
+                    self.tmp |= (self.pop_byte() as usize) << 8;
+                } else if self.clock_counter == 20 {
+                    self.registers.pc = self.tmp as u16;
                 }
             }
             InstKind::ReturnEnableInterrupt => {
-                if self.clock_counter < self.clocks_to_finish {
-                    return;
+                if self.clock_counter == 8 {
+                    self.tmp = self.pop_byte() as usize;
+                } else if self.clock_counter == 12 {
+                    self.tmp |= (self.pop_byte() as usize) << 8;
+                } else if self.clock_counter == 16 {
+                    self.registers.pc = self.tmp as u16;
+                    self.memory.borrow_mut().interrupt_master_enable = true;
                 }
-                let addr = self.memory.borrow().get_word(self.registers.sp);
-                self.registers.pc = addr;
-                self.registers.sp += 2;
-                self.memory.borrow_mut().interrupt_master_enable = true;
             }
             InstKind::Restart(addr) => {
-                if self.clock_counter < self.clocks_to_finish {
+                let [lo, hi] = self.registers.pc.to_le_bytes();
+                if self.clock_counter == 12 {
+                    self.push_byte(hi);
+                } else if self.clock_counter == 16 {
+                    self.push_byte(lo);
+                    self.registers.pc = addr;
+                }
+            }
+            InstKind::Interrupt => {
+                // internal M-cycles, the two pushes of PC, then the jump to the handler.
+                // the handler is chosen after the high byte is pushed, so if the push
+                // overwrites IE and no interrupt is left, PC is set to 0x0000 instead.
+                // see https://gbdev.io/pandocs/Interrupts.html and mooneye's ie_push test.
+                let [lo, hi] = self.registers.pc.to_le_bytes();
+                if self.clock_counter == self.clocks_to_finish - 8 {
+                    self.push_byte(hi);
+                } else if self.clock_counter == self.clocks_to_finish - 4 {
+                    self.tmp = {
+                        let mut memory = self.memory.borrow_mut();
+                        let interrupt = memory.interrupt_flag & memory.interrupt_enable & 0x1f;
+                        if interrupt != 0 {
+                            let i = interrupt.trailing_zeros() as usize;
+                            memory.interrupt_flag &= !(1 << i);
+                            INTERRUPT_HANDLER[i] as usize
+                        } else {
+                            0x0000
+                        }
+                    };
+                    self.push_byte(lo);
+                } else if self.clock_counter == self.clocks_to_finish {
+                    self.registers.pc = self.tmp as u16;
+                }
+            }
+            InstKind::Prefix => {
+                // the second opcode byte is fetched on M2 like an immediate operand,
+                // then the instruction continues with the timing of the `CB xx` table.
+                if self.clock_counter < 8 {
                     return;
                 }
-                self.registers.sp -= 2;
-                self.memory
-                    .borrow_mut()
-                    .set_word(self.registers.sp, self.registers.pc);
-                self.registers.pc = addr;
+                let opcode = self.memory.borrow().get_byte(self.registers.pc);
+                self.registers.pc = self.registers.pc.wrapping_add(1);
+                let inst = self.sub_inst_table[opcode as usize];
+                self.current_inst = Some(inst.kind);
+                self.clocks_to_finish = inst.clocks;
+                self.execute(inst.kind);
             }
         }
     }
 
     pub fn tick(&mut self) {
+        if self.is_locked_up {
+            // an illegal opcode hangs the CPU until the power is cycled.
+            // interrupts are not serviced anymore.
+            return;
+        }
+        {
+            // the CPU is paused during VRAM DMA transfers and speed switches.
+            let mut memory = self.memory.borrow_mut();
+            if memory.cpu_stall_clocks > 0 {
+                memory.cpu_stall_clocks -= 1;
+                return;
+            }
+        }
         if self.current_inst.is_none() {
             let interrupt =
                 self.memory.borrow().interrupt_flag & self.memory.borrow().interrupt_enable & 0x1f;
@@ -1279,19 +1172,17 @@ impl CPU {
                 self.clocks_to_finish = 4;
                 self.clock_counter = 0;
             } else if self.memory.borrow().interrupt_master_enable && interrupt != 0 {
-                for (i, addr) in INTERRUPT_HANDLER.iter().enumerate() {
-                    if interrupt & (1 << i) != 0 {
-                        self.memory.borrow_mut().interrupt_master_enable = false;
-                        self.memory.borrow_mut().interrupt_flag ^= 1 << i;
-                        self.current_inst = Some(InstKind::CallImm(*addr));
-                        self.clocks_to_finish = 20;
-                        self.clock_counter = 0;
-                        break;
-                    }
-                }
+                self.memory.borrow_mut().interrupt_master_enable = false;
+                self.current_inst = Some(InstKind::Interrupt);
+                // waking up from HALT takes one more M-cycle before the dispatch.
+                self.clocks_to_finish = if self.is_halt { 24 } else { 20 };
+                self.clock_counter = 0;
                 self.is_halt = false;
             } else {
-                let inst = self.decode();
+                let Some(inst) = self.decode() else {
+                    self.is_locked_up = true;
+                    return;
+                };
                 self.current_inst = Some(inst.kind);
                 self.clocks_to_finish = inst.clocks;
                 self.clock_counter = 0;
@@ -1301,6 +1192,7 @@ impl CPU {
         self.clock_counter += 1;
         if (self.clock_counter & 0x3) == 0 {
             let inst = self.current_inst.unwrap();
+            self.fetch_operand(inst);
             self.execute(inst);
         }
         if self.clock_counter == self.clocks_to_finish {
@@ -1313,6 +1205,48 @@ impl CPU {
     }
 }
 
+impl Snapshot for CPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.registers.save_state(writer);
+        instruction::write_option_inst_kind(writer, self.prev_inst);
+        instruction::write_option_inst_kind(writer, self.current_inst);
+        writer.write_usize(self.clocks_to_finish);
+        writer.write_usize(self.clock_counter);
+        writer.write_usize(self.tmp);
+        writer.write_bool(self.is_halt);
+        writer.write_bool(self.is_halt_bug_occured);
+        writer.write_bool(self.is_locked_up);
+        writer.write_bool(self.is_stopped);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(reader)?;
+        self.prev_inst = instruction::read_option_inst_kind(reader)?;
+        self.current_inst = instruction::read_option_inst_kind(reader)?;
+        self.clocks_to_finish = reader.read_usize()?;
+        self.clock_counter = reader.read_usize()?;
+        if let Some(inst) = self.current_inst {
+            // an interrupt dispatch takes 5 M-cycles, or 6 when it wakes the CPU from HALT.
+            let valid_length = match inst {
+                InstKind::Interrupt => self.clocks_to_finish == 20 || self.clocks_to_finish == 24,
+                _ => (4..=24).contains(&self.clocks_to_finish) && self.clocks_to_finish & 0x3 == 0,
+            };
+            if !valid_length || self.clock_counter >= self.clocks_to_finish {
+                return Err(StateError::InvalidData("instruction timing"));
+            }
+        }
+        self.tmp = reader.read_usize()?;
+        if self.tmp > 0xffff {
+            return Err(StateError::InvalidData("instruction operand"));
+        }
+        self.is_halt = reader.read_bool()?;
+        self.is_halt_bug_occured = reader.read_bool()?;
+        self.is_locked_up = reader.read_bool()?;
+        self.is_stopped = reader.read_bool()?;
+        Ok(())
+    }
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 pub struct Registers {
     pub a: u8,
@@ -1329,7 +1263,7 @@ pub struct Registers {
 
 impl Registers {
     pub fn get_af(&self) -> u16 {
-        return ((self.a as u16) << 8) | self.f as u16;
+        ((self.a as u16) << 8) | self.f as u16
     }
 
     pub fn set_af(&mut self, value: u16) {
@@ -1338,7 +1272,7 @@ impl Registers {
     }
 
     pub fn get_bc(&self) -> u16 {
-        return ((self.b as u16) << 8) | self.c as u16;
+        ((self.b as u16) << 8) | self.c as u16
     }
 
     pub fn set_bc(&mut self, value: u16) {
@@ -1347,7 +1281,7 @@ impl Registers {
     }
 
     pub fn get_de(&self) -> u16 {
-        return ((self.d as u16) << 8) | self.e as u16;
+        ((self.d as u16) << 8) | self.e as u16
     }
 
     pub fn set_de(&mut self, value: u16) {
@@ -1356,7 +1290,7 @@ impl Registers {
     }
 
     pub fn get_hl(&self) -> u16 {
-        return ((self.h as u16) << 8) | self.l as u16;
+        ((self.h as u16) << 8) | self.l as u16
     }
 
     pub fn set_hl(&mut self, value: u16) {
@@ -1365,6 +1299,35 @@ impl Registers {
     }
 }
 
+impl Snapshot for Registers {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.a);
+        writer.write_u8(self.b);
+        writer.write_u8(self.c);
+        writer.write_u8(self.d);
+        writer.write_u8(self.e);
+        writer.write_u8(self.f);
+        writer.write_u8(self.h);
+        writer.write_u8(self.l);
+        writer.write_u16(self.sp);
+        writer.write_u16(self.pc);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.a = reader.read_u8()?;
+        self.b = reader.read_u8()?;
+        self.c = reader.read_u8()?;
+        self.d = reader.read_u8()?;
+        self.e = reader.read_u8()?;
+        self.f = reader.read_u8()? & 0xf0;
+        self.h = reader.read_u8()?;
+        self.l = reader.read_u8()?;
+        self.sp = reader.read_u16()?;
+        self.pc = reader.read_u16()?;
+        Ok(())
+    }
+}
+
 #[derive(Default, Clone, Copy, Debug)]
 pub struct Flags {
     pub z: bool,
@@ -1384,21 +1347,262 @@ impl From<u8> for Flags {
     }
 }
 
-impl Into<u8> for Flags {
-    fn into(self) -> u8 {
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> u8 {
         let mut value = 0;
-        if self.z {
+        if flags.z {
             value |= 1 << 7;
         }
-        if self.n {
+        if flags.n {
             value |= 1 << 6;
         }
-        if self.h {
+        if flags.h {
             value |= 1 << 5;
         }
-        if self.c {
+        if flags.c {
             value |= 1 << 4;
         }
         value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::StateReader;
+
+    fn reload(cpu: &CPU) -> Result<(), StateError> {
+        let mut writer = StateWriter::new();
+        cpu.save_state(&mut writer);
+        let state = writer.finish();
+        let mut reader = StateReader::new(&state)?;
+        CPU::new(Rc::clone(&cpu.memory)).load_state(&mut reader)
+    }
+
+    #[test]
+    fn load_state_rejects_invalid_timing() {
+        // (instruction in flight, clocks to finish, clock counter, valid)
+        let cases = [
+            (None, 0, 0, true),
+            (Some(InstKind::Nop), 4, 3, true),
+            (Some(InstKind::Interrupt), 24, 10, true),
+            (Some(InstKind::Interrupt), 4, 0, false),
+            (Some(InstKind::CallImm), 26, 0, false),
+            (Some(InstKind::Nop), 4, 4, false),
+        ];
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        for &(inst, clocks_to_finish, clock_counter, valid) in cases.iter() {
+            let mut cpu = CPU::new(Rc::clone(&memory));
+            cpu.current_inst = inst;
+            cpu.clocks_to_finish = clocks_to_finish;
+            cpu.clock_counter = clock_counter;
+            assert_eq!(reload(&cpu).is_ok(), valid, "{:?}", inst);
+        }
+    }
+
+    #[test]
+    fn cb_opcode_is_fetched_on_m2() {
+        // (second opcode byte, clocks)
+        let cases = [
+            (0x37, 8),  // SWAP A
+            (0x46, 12), // BIT 0, (HL)
+            (0x06, 16), // RLC (HL)
+        ];
+        for &(opcode, clocks) in cases.iter() {
+            let memory = Rc::new(RefCell::new(Memory::new()));
+            memory.borrow_mut().set_byte(0xc000, 0xcb);
+            memory.borrow_mut().set_byte(0xc001, opcode);
+            let mut cpu = CPU::new(Rc::clone(&memory));
+            cpu.registers.pc = 0xc000;
+            cpu.registers.set_hl(0xc100);
+            for _ in 0..4 {
+                cpu.tick();
+            }
+            assert_eq!(cpu.registers.pc, 0xc001, "{:02x}", opcode);
+            for _ in 4..8 {
+                cpu.tick();
+            }
+            assert_eq!(cpu.registers.pc, 0xc002, "{:02x}", opcode);
+            for _ in 8..clocks {
+                cpu.tick();
+            }
+            assert!(cpu.current_inst.is_none(), "{:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn interrupt_dispatch_and_ie_push() {
+        // (PC, SP, IF, IE, PC after the dispatch, IE after, IF after)
+        let cases = [
+            (0x0200, 0xd000, 0x01, 0x01, 0x0040, 0x01, 0x00),
+            // the high byte of PC is pushed to IE and disables VBlank, so PC is set to 0.
+            (0x0200, 0x0000, 0x01, 0x01, 0x0000, 0x02, 0x01),
+            // IE still has VBlank enabled after the push.
+            (0x0100, 0x0000, 0x01, 0x01, 0x0040, 0x01, 0x00),
+            // the push enables the timer interrupt instead, which is dispatched.
+            (0x0400, 0x0000, 0x05, 0x01, 0x0050, 0x04, 0x01),
+        ];
+        for &(pc, sp, interrupt_flag, interrupt_enable, new_pc, new_ie, new_if) in cases.iter() {
+            let memory = Rc::new(RefCell::new(Memory::new()));
+            memory.borrow_mut().interrupt_flag = interrupt_flag;
+            memory.borrow_mut().interrupt_enable = interrupt_enable;
+            memory.borrow_mut().interrupt_master_enable = true;
+            let mut cpu = CPU::new(Rc::clone(&memory));
+            cpu.registers.pc = pc;
+            cpu.registers.sp = sp;
+            for _ in 0..20 {
+                cpu.tick();
+            }
+            assert!(cpu.current_inst.is_none());
+            assert_eq!(cpu.registers.pc, new_pc, "PC {:#06x}, SP {:#06x}", pc, sp);
+            assert_eq!(cpu.registers.sp, sp.wrapping_sub(2));
+            assert_eq!(memory.borrow().interrupt_enable, new_ie);
+            assert_eq!(memory.borrow().interrupt_flag & 0x1f, new_if);
+            assert!(!memory.borrow().interrupt_master_enable);
+        }
+    }
+
+    #[test]
+    fn pc_and_hl_wrap_around() {
+        // (opcode, PC, HL, PC after, HL after)
+        let cases = [
+            (0x00, 0xffff, 0x0000, 0x0000, 0x0000), // NOP
+            (0x22, 0xc000, 0xffff, 0xc001, 0x0000), // LD (HL+), A
+            (0x2a, 0xc000, 0xffff, 0xc001, 0x0000), // LD A, (HL+)
+            (0x32, 0xc000, 0x0000, 0xc001, 0xffff), // LD (HL-), A
+            (0x3a, 0xc000, 0x0000, 0xc001, 0xffff), // LD A, (HL-)
+        ];
+        for &(opcode, pc, hl, new_pc, new_hl) in cases.iter() {
+            let memory = Rc::new(RefCell::new(Memory::new()));
+            memory.borrow_mut().set_byte(pc, opcode);
+            let mut cpu = CPU::new(Rc::clone(&memory));
+            cpu.registers.pc = pc;
+            cpu.registers.set_hl(hl);
+            while cpu.current_inst.is_some() || cpu.registers.pc == pc {
+                cpu.tick();
+            }
+            assert_eq!(cpu.registers.pc, new_pc, "{:02x}", opcode);
+            assert_eq!(cpu.registers.get_hl(), new_hl, "{:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn illegal_opcode_locks_up() {
+        for &opcode in [
+            0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd,
+        ]
+        .iter()
+        {
+            let memory = Rc::new(RefCell::new(Memory::new()));
+            memory.borrow_mut().set_byte(0xc000, opcode);
+            memory.borrow_mut().interrupt_flag = 0x01;
+            let mut cpu = CPU::new(Rc::clone(&memory));
+            cpu.registers.pc = 0xc000;
+            cpu.tick();
+            assert!(cpu.is_locked_up, "{:02x}", opcode);
+            // pending interrupts are not serviced anymore.
+            memory.borrow_mut().interrupt_enable = 0x01;
+            memory.borrow_mut().interrupt_master_enable = true;
+            for _ in 0..64 {
+                cpu.tick();
+            }
+            assert!(cpu.current_inst.is_none(), "{:02x}", opcode);
+            assert_eq!(cpu.registers.pc, 0xc001, "{:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn instruction_timing() {
+        // (instruction bytes, F, clocks, PC after)
+        // the program starts at 0xC000 with SP = 0xD000, HL = 0xC100,
+        // and 0xC300 is the return address on the stack.
+        let z = 0x80;
+        let c = 0x10;
+        let cases: [(&[u8], u8, usize, u16); 31] = [
+            (&[0xc3, 0x00, 0xc2], 0, 16, 0xc200), // JP nn
+            (&[0xc2, 0x00, 0xc2], 0, 16, 0xc200), // JP NZ, nn
+            (&[0xc2, 0x00, 0xc2], z, 12, 0xc003),
+            (&[0xca, 0x00, 0xc2], z, 16, 0xc200), // JP Z, nn
+            (&[0xca, 0x00, 0xc2], 0, 12, 0xc003),
+            (&[0xd2, 0x00, 0xc2], c, 12, 0xc003), // JP NC, nn
+            (&[0xda, 0x00, 0xc2], c, 16, 0xc200), // JP C, nn
+            (&[0xe9], 0, 4, 0xc100),              // JP (HL)
+            (&[0x18, 0x10], 0, 12, 0xc012),       // JR e
+            (&[0x20, 0xfe], 0, 12, 0xc000),       // JR NZ, e
+            (&[0x20, 0xfe], z, 8, 0xc002),
+            (&[0x38, 0x10], c, 12, 0xc012), // JR C, e
+            (&[0x38, 0x10], 0, 8, 0xc002),
+            (&[0xcd, 0x00, 0xc2], 0, 24, 0xc200), // CALL nn
+            (&[0xc4, 0x00, 0xc2], 0, 24, 0xc200), // CALL NZ, nn
+            (&[0xc4, 0x00, 0xc2], z, 12, 0xc003),
+            (&[0xdc, 0x00, 0xc2], c, 24, 0xc200), // CALL C, nn
+            (&[0xdc, 0x00, 0xc2], 0, 12, 0xc003),
+            (&[0xc9], 0, 16, 0xc300), // RET
+            (&[0xd9], 0, 16, 0xc300), // RETI
+            (&[0xc0], 0, 20, 0xc300), // RET NZ
+            (&[0xc0], z, 8, 0xc001),
+            (&[0xd8], c, 20, 0xc300), // RET C
+            (&[0xd8], 0, 8, 0xc001),
+            (&[0xff], 0, 16, 0x0038),             // RST 38h
+            (&[0x08, 0x00, 0xc2], 0, 20, 0xc003), // LD (nn), SP
+            (&[0xc5], 0, 16, 0xc001),             // PUSH BC
+            (&[0xc1], 0, 12, 0xc001),             // POP BC
+            (&[0x34], 0, 12, 0xc001),             // INC (HL)
+            (&[0x36, 0x12], 0, 12, 0xc002),       // LD (HL), n
+            (&[0xcb, 0xc6], 0, 16, 0xc002),       // SET 0, (HL)
+        ];
+        for &(program, f, clocks, pc) in cases.iter() {
+            let memory = Rc::new(RefCell::new(Memory::new()));
+            for (i, &byte) in program.iter().enumerate() {
+                memory.borrow_mut().set_byte(0xc000 + i as u16, byte);
+            }
+            memory.borrow_mut().set_byte(0xd000, 0x00);
+            memory.borrow_mut().set_byte(0xd001, 0xc3);
+            let mut cpu = CPU::new(Rc::clone(&memory));
+            cpu.registers.pc = 0xc000;
+            cpu.registers.sp = 0xd000;
+            cpu.registers.f = f;
+            cpu.registers.set_hl(0xc100);
+            let mut n = 0;
+            loop {
+                cpu.tick();
+                n += 1;
+                if cpu.current_inst.is_none() || n > 32 {
+                    break;
+                }
+            }
+            assert_eq!(n, clocks, "{:02x?}, F {:#04x}", program, f);
+            assert_eq!(cpu.registers.pc, pc, "{:02x?}, F {:#04x}", program, f);
+        }
+    }
+
+    #[test]
+    fn stack_and_read_modify_write_effects() {
+        let memory = Rc::new(RefCell::new(Memory::new()));
+        // LD (0xC200), SP; PUSH BC; POP DE; INC (HL); SET 7, (HL)
+        let program = [0x08, 0x00, 0xc2, 0xc5, 0xd1, 0x34, 0xcb, 0xfe];
+        for (i, &byte) in program.iter().enumerate() {
+            memory.borrow_mut().set_byte(0xc000 + i as u16, byte);
+        }
+        memory.borrow_mut().set_byte(0xc100, 0x0f);
+        let mut cpu = CPU::new(Rc::clone(&memory));
+        cpu.registers.pc = 0xc000;
+        cpu.registers.sp = 0xd012;
+        cpu.registers.b = 0x12;
+        cpu.registers.c = 0x34;
+        cpu.registers.set_hl(0xc100);
+        for _ in 0..20 + 16 + 12 + 12 + 16 {
+            cpu.tick();
+        }
+        assert!(cpu.current_inst.is_none());
+        assert_eq!(cpu.registers.pc, 0xc008);
+        let memory = memory.borrow();
+        assert_eq!(memory.get_byte(0xc200), 0x12);
+        assert_eq!(memory.get_byte(0xc201), 0xd0);
+        assert_eq!(memory.get_byte(0xd010), 0x34);
+        assert_eq!(memory.get_byte(0xd011), 0x12);
+        assert_eq!((cpu.registers.d, cpu.registers.e), (0x12, 0x34));
+        assert_eq!(cpu.registers.sp, 0xd012);
+        assert_eq!(memory.get_byte(0xc100), 0x90);
+    }
+}
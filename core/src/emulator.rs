@@ -1,10 +1,14 @@
 extern crate console_error_panic_hook;
-use crate::apu::APU;
-use crate::cpu::{Flags, CPU};
-use crate::memory::Memory;
+use crate::apu::{APU, AUDIO_CHANNELS};
+use crate::cartridge::CartridgeInfo;
+use crate::cpu::{Flags, Registers, CPU};
+use crate::gbs::{self, Gbs, GbsInfo};
+use crate::mapper::{self, rtc::RTC_FOOTER_SIZE};
+use crate::memory::{self, Memory};
 use crate::ppu::PPU;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 use crate::timer::Timer;
-use js_sys;
+use crate::wav;
 use std::cell::RefCell;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
@@ -32,6 +36,7 @@ pub struct JoypadInput {
 
 #[wasm_bindgen]
 impl JoypadInput {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         start: bool,
         select: bool,
@@ -62,6 +67,8 @@ pub struct Emulator {
     apu: APU,
     timer: Timer,
     memory: Rc<RefCell<Memory>>,
+    cartridge_info: Option<CartridgeInfo>,
+    gbs: Option<Gbs>,
     joypad_input: JoypadInput,
     transferring_data: bool,
     pub running: bool,
@@ -78,6 +85,8 @@ impl Emulator {
             apu: APU::new(Rc::clone(&memory)),
             timer: Timer::new(Rc::clone(&memory)),
             memory,
+            cartridge_info: None,
+            gbs: None,
             joypad_input: JoypadInput::default(),
             transferring_data: false,
             running: false,
@@ -93,6 +102,8 @@ impl Emulator {
     }
 
     // set the emulator state as if the boot ROM has been executed.
+    // CGB compatible cartridges get the registers left by the CGB boot ROM, so init()
+    // can also be used to reset the emulator after load_rom.
     // see https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
     pub fn init(&mut self) {
         self.cpu.registers.a = 0x00;
@@ -116,9 +127,8 @@ impl Emulator {
 
         let mut memory = self.memory.borrow_mut();
 
-        memory.rom_bank_number = 1;
         memory.joypad = 0xcf;
-        memory.divider = 0xab;
+        memory.system_counter = 0xabcc;
         memory.timer = 0x00;
         memory.timer_modulo = 0x00;
         memory.timer_control = 0xf8;
@@ -127,6 +137,8 @@ impl Emulator {
         memory.nr22 = 0x00;
         memory.nr23 = 0xff;
         memory.nr24 = 0xbf;
+        memory.nr50 = 0x77;
+        memory.nr51 = 0xf3;
         memory.nr52 = 0xf1;
         memory.lcd_control = 0x91;
         memory.lcd_status = 0x85;
@@ -138,33 +150,199 @@ impl Emulator {
         // obj palettes are left entirely uninitialized.
         memory.wy = 0x00;
         memory.wx = 0x00;
+        memory.cgb_mode = false;
+        memory.dmg_compat = false;
+        memory.double_speed = false;
+        memory.speed_switch_armed = false;
+        memory.vram_bank = 0;
+        memory.wram_bank = 1;
+        drop(memory);
+
+        if self.is_cgb_cartridge() {
+            self.init_cgb();
+        }
     }
 
-    pub fn load_rom(&mut self, rom_data: &[u8]) {
+    pub fn load_rom(&mut self, rom_data: &[u8]) -> Result<(), JsError> {
         console_error_panic_hook::set_once();
-        let n = rom_data.len();
-        self.memory.borrow_mut().cart_rom[0..n].copy_from_slice(rom_data);
-        self.memory.borrow_mut().cart_type = rom_data[0x147];
-        self.memory.borrow_mut().rom_size = rom_data[0x148];
-        self.memory.borrow_mut().ram_size = rom_data[0x149];
+        let info = CartridgeInfo::parse(rom_data)?;
+        // a boot ROM loaded for the previous cartridge must be loaded again.
+        self.memory.borrow_mut().boot_rom = None;
+        self.memory.borrow_mut().mapper = mapper::from_rom(rom_data.to_vec());
+        self.cartridge_info = Some(info);
+        self.gbs = None;
+        if self.is_cgb_cartridge() {
+            self.init_cgb();
+        }
+        Ok(())
     }
 
-    pub fn load_savedata(&mut self, savedata: &[u8]) {
+    // load a GBS music file and start playing its first track.
+    pub fn load_gbs(&mut self, gbs_data: &[u8]) -> Result<(), JsError> {
         console_error_panic_hook::set_once();
-        let n = savedata.len();
-        self.memory.borrow_mut().cart_ram[0..n].copy_from_slice(savedata);
+        let gbs = Gbs::parse(gbs_data)?;
+        // the emulator is only changed once the file and its first track are known to be valid.
+        let first_track = gbs.info.first_track;
+        gbs.info.check_track(first_track)?;
+        self.cartridge_info = None;
+        self.gbs = Some(gbs);
+        self.select_gbs_track(first_track)
+    }
+
+    pub fn get_gbs_info(&self) -> Option<GbsInfo> {
+        self.gbs.as_ref().map(|gbs| gbs.info.clone())
+    }
+
+    // restart the machine and call the init routine of the GBS file with a 1-based track number.
+    // the play routine is then called from the VBlank or timer interrupt, as the header requests.
+    pub fn select_gbs_track(&mut self, track: u8) -> Result<(), JsError> {
+        let Some(gbs) = &self.gbs else {
+            return Err(JsError::new("no GBS file is loaded"));
+        };
+        gbs.info.check_track(track)?;
+        let info = gbs.info.clone();
+        let image = gbs.image.clone();
+
+        self.init();
+        self.cpu.registers.a = track - 1;
+        self.cpu.registers.sp = info.stack_pointer;
+        self.cpu.registers.pc = gbs::DRIVER_ADDRESS;
+        self.cpu.current_inst = None;
+        self.cpu.prev_inst = None;
+        self.cpu.is_halt = false;
+        self.cpu.is_locked_up = false;
+        self.cpu.is_stopped = false;
+        self.apu.clear_audio_buffer();
+
+        let mut memory = self.memory.borrow_mut();
+        memory.mapper = mapper::for_gbs(image);
+        memory.work_ram.fill(0);
+        memory.high_ram.fill(0);
+        memory.interrupt_flag = 0xe0;
+        memory.interrupt_master_enable = false;
+        memory.nr50 = 0x77;
+        memory.nr51 = 0xff;
+        memory.nr52 = 0x80;
+        if info.uses_timer() {
+            memory.timer_modulo = info.timer_modulo;
+            memory.timer_control = 0xf8 | (info.timer_control & 0x07);
+            memory.interrupt_enable = 0x04;
+            // TAC bit 7 asks for CGB double speed, which doubles the timer rate.
+            memory.double_speed = info.timer_control & 0x80 != 0;
+        } else {
+            memory.interrupt_enable = 0x01;
+        }
+        Ok(())
+    }
+
+    pub fn get_cartridge_info(&self) -> Option<CartridgeInfo> {
+        self.cartridge_info.clone()
+    }
+
+    // map a DMG (256 bytes) or CGB (2304 bytes) boot ROM and start execution from 0x0000.
+    // call it after load_rom; the boot ROM sets up the registers itself instead of init().
+    pub fn load_boot_rom(&mut self, boot_rom: &[u8]) -> Result<(), JsError> {
+        console_error_panic_hook::set_once();
+        self.memory.borrow_mut().load_boot_rom(boot_rom)?;
+        self.cpu.registers = Registers::default();
+        self.cpu.current_inst = None;
+        self.cpu.prev_inst = None;
+        self.cpu.is_halt = false;
+        self.cpu.is_locked_up = false;
+        self.cpu.is_stopped = false;
+
+        let mut memory = self.memory.borrow_mut();
+        // the CGB boot ROM runs in CGB mode and selects the DMG compatibility mode
+        // itself through KEY0 for DMG cartridges.
+        memory.cgb_mode = boot_rom.len() == memory::CGB_BOOT_ROM_SIZE;
+        memory.dmg_compat = false;
+        memory.key0 = 0;
+        memory.system_counter = 0x0000;
+        memory.lcd_control = 0x00;
+        memory.bg_palette = 0x00;
+        memory.nr52 = 0x00;
+        memory.interrupt_flag = 0xe0;
+        memory.interrupt_master_enable = false;
+        Ok(())
+    }
+
+    pub fn load_savedata(&mut self, savedata: &[u8]) -> Result<(), JsError> {
+        console_error_panic_hook::set_once();
+        let mut memory = self.memory.borrow_mut();
+        let mut n = savedata.len();
+        if memory.mapper.rtc().is_some() {
+            // the RTC footer is either 48 bytes or 44 bytes (32-bit timestamp) long.
+            let footer_size = n % 0x2000;
+            if footer_size == RTC_FOOTER_SIZE || footer_size == RTC_FOOTER_SIZE - 4 {
+                n -= footer_size;
+            }
+        }
+        // the RAM is only written once the size is validated, and the RTC after that,
+        // so a rejected file leaves the cartridge untouched.
+        memory.mapper.load_savedata(&savedata[0..n])?;
+        if let Some(rtc) = memory.mapper.rtc_mut() {
+            if n < savedata.len() {
+                rtc.load_footer(&savedata[n..]);
+            }
+        }
+        Ok(())
     }
 
     pub fn get_savedata(&self) -> Vec<u8> {
-        self.memory.borrow().cart_ram[..].into()
+        let memory = self.memory.borrow();
+        let mut savedata = memory.mapper.savedata();
+        if let Some(rtc) = memory.mapper.rtc() {
+            let timestamp = (js_sys::Date::now() / 1000.0) as u64;
+            savedata.extend_from_slice(&rtc.footer(timestamp));
+        }
+        savedata
+    }
+
+    // take a snapshot of the whole machine.
+    // the cartridge ROM is not included, so the same ROM must be loaded before restoring it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.cpu.save_state(&mut writer);
+        self.ppu.save_state(&mut writer);
+        self.apu.save_state(&mut writer);
+        self.timer.save_state(&mut writer);
+        self.memory.borrow().save_state(&mut writer);
+        writer.write_bool(self.transferring_data);
+        writer.finish()
+    }
+
+    // restore a snapshot taken by `save_state`.
+    // if the snapshot is rejected, the emulator is left untouched.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsError> {
+        let backup = self.save_state();
+        if let Err(err) = self.restore_state(state) {
+            self.restore_state(&backup)
+                .expect("failed to roll back to the previous state");
+            return Err(err.into());
+        }
+        Ok(())
     }
 
     pub fn tick(&mut self) {
         self.update_joypad();
+        self.memory.borrow_mut().mapper.tick();
+        if self.cpu.is_stopped {
+            // the system clock is stopped, so the screen stays blank and the APU is frozen.
+            self.apu.tick_stopped();
+            return;
+        }
         self.timer.tick();
         self.ppu.tick();
         self.apu.tick();
+        self.memory.borrow_mut().tick_oam_dma();
         self.cpu.tick();
+        if self.memory.borrow().double_speed {
+            // in double speed mode, the CPU, the timer and OAM DMA run twice as fast
+            // as the PPU and the APU.
+            self.timer.tick();
+            self.memory.borrow_mut().tick_oam_dma();
+            self.cpu.tick();
+        }
     }
 
     pub fn next_frame(&mut self) {
@@ -174,16 +352,81 @@ impl Emulator {
         for _ in 0..CLOCKS_PER_FRAME {
             self.tick();
         }
+        self.apu.end_frame();
     }
 
     pub fn get_frame_buffer(&self) -> Vec<u8> {
         self.ppu.frame_buffer.into()
     }
 
+    // samples are interleaved by channel. see get_audio_channels.
     pub fn get_audio_buffer(&self) -> Vec<f32> {
         self.apu.audio_buffer.clone()
     }
 
+    // the output sample rate of get_audio_buffer (48000 Hz by default).
+    // it cannot be changed during a recording, as a WAV file has a single sample rate.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), JsError> {
+        if !(8000..=192000).contains(&sample_rate) {
+            return Err(JsError::new(&format!(
+                "unsupported sample rate {} Hz",
+                sample_rate
+            )));
+        }
+        if self.apu.recording.is_some() {
+            return Err(JsError::new(
+                "the sample rate cannot be changed while recording audio",
+            ));
+        }
+        self.apu.set_sample_rate(sample_rate);
+        Ok(())
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.apu.sample_rate()
+    }
+
+    // bit n of the mask enables channel n + 1 (0x1: pulse 1, 0x2: pulse 2, 0x4: wave, 0x8: noise).
+    // a channel can be soloed by passing only its bit.
+    pub fn set_channel_mask(&mut self, mask: u8) {
+        self.apu.channel_mask = mask & 0xf;
+    }
+
+    pub fn get_channel_mask(&self) -> u8 {
+        self.apu.channel_mask
+    }
+
+    // the output of a single channel (0-3) in this frame, one value per output sample.
+    // it is taken before the channel mask and the panning are applied.
+    pub fn get_channel_buffer(&self, channel: usize) -> Result<Vec<f32>, JsError> {
+        match self.apu.channel_buffers.get(channel) {
+            Some(buffer) => Ok(buffer.clone()),
+            None => Err(JsError::new(&format!("no such channel {}", channel))),
+        }
+    }
+
+    // capture everything the APU outputs until finish_audio_recording is called.
+    // unlike get_audio_buffer, the recording is not cleared every frame.
+    pub fn start_audio_recording(&mut self) {
+        self.apu.recording = Some((self.apu.sample_rate(), Vec::new()));
+    }
+
+    // returns the recording as a 16-bit PCM WAV file.
+    // the file has no samples if start_audio_recording hasn't been called.
+    pub fn finish_audio_recording(&mut self) -> Vec<u8> {
+        let (sample_rate, samples) = self
+            .apu
+            .recording
+            .take()
+            .unwrap_or_else(|| (self.apu.sample_rate(), Vec::new()));
+        wav::encode(&samples, sample_rate, AUDIO_CHANNELS as u16)
+    }
+
+    // number of interleaved channels in get_audio_buffer (2: left, right).
+    pub fn get_audio_channels(&self) -> usize {
+        AUDIO_CHANNELS
+    }
+
     pub fn update_joypad_input(&mut self, joypad_input: JoypadInput) {
         self.joypad_input = joypad_input;
     }
@@ -225,6 +468,10 @@ impl Emulator {
                 self.memory.borrow_mut().interrupt_flag |= 1 << 4;
             }
         }
+        // any selected joypad line going low wakes the system from STOP.
+        if next_joypad & 0xf != 0xf {
+            self.cpu.is_stopped = false;
+        }
         self.memory.borrow_mut().joypad = next_joypad;
     }
 
@@ -248,3 +495,161 @@ impl Emulator {
         prev
     }
 }
+
+impl Emulator {
+    fn is_cgb_cartridge(&self) -> bool {
+        self.cartridge_info
+            .as_ref()
+            .is_some_and(|info| info.cgb_flag & 0x80 != 0)
+    }
+
+    // CGB compatible cartridges start in CGB mode with the registers left by the CGB boot ROM.
+    fn init_cgb(&mut self) {
+        self.cpu.registers.a = 0x11;
+        self.cpu.registers.f = Flags {
+            z: true,
+            n: false,
+            h: false,
+            c: false,
+        }
+        .into();
+        self.cpu.registers.b = 0x00;
+        self.cpu.registers.c = 0x00;
+        self.cpu.registers.d = 0xff;
+        self.cpu.registers.e = 0x56;
+        self.cpu.registers.h = 0x00;
+        self.cpu.registers.l = 0x0d;
+
+        let mut memory = self.memory.borrow_mut();
+        memory.cgb_mode = true;
+        // the boot ROM initializes all background colors to white.
+        memory.bg_palette_ram = [0xff; 64];
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(state)?;
+        self.cpu.load_state(&mut reader)?;
+        self.ppu.load_state(&mut reader)?;
+        self.apu.load_state(&mut reader)?;
+        self.timer.load_state(&mut reader)?;
+        self.memory.borrow_mut().load_state(&mut reader)?;
+        self.transferring_data = reader.read_bool()?;
+        if !reader.is_empty() {
+            return Err(StateError::InvalidData("trailing bytes"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // init stores the track number (A) to 0xC000, play counts its calls at 0xC001.
+    fn gbs_file(timer_control: u8) -> Vec<u8> {
+        let mut data = vec![0; 0x70];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[0x04] = 3; // track count
+        data[0x05] = 1; // first track
+        data[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes()); // load
+        data[0x08..0x0a].copy_from_slice(&0x0400u16.to_le_bytes()); // init
+        data[0x0a..0x0c].copy_from_slice(&0x0404u16.to_le_bytes()); // play
+        data[0x0c..0x0e].copy_from_slice(&0xdffeu16.to_le_bytes());
+        data[0x0e] = 0xc0;
+        data[0x0f] = timer_control;
+        data.extend_from_slice(&[0xea, 0x00, 0xc0, 0xc9]); // LD (0xC000), A; RET
+        data.extend_from_slice(&[0x21, 0x01, 0xc0, 0x34, 0xc9]); // LD HL, 0xC001; INC (HL); RET
+        data
+    }
+
+    #[test]
+    fn gbs_track_selection() {
+        // (TAC, track, IE)
+        let cases = [(0x00, 1, 0x01), (0x00, 3, 0x01), (0x04, 2, 0x04)];
+        for &(timer_control, track, interrupt_enable) in cases.iter() {
+            let mut emulator = Emulator::new();
+            emulator.load_gbs(&gbs_file(timer_control)).unwrap();
+            emulator.select_gbs_track(track).unwrap();
+            assert_eq!(emulator.memory.borrow().interrupt_enable, interrupt_enable);
+            // TIMA starts from 0, so the first timer overflow takes 256 ticks at 4096 Hz.
+            for _ in 0..CLOCKS_PER_FRAME * 4 {
+                emulator.tick();
+            }
+            let memory = emulator.memory.borrow();
+            assert_eq!(
+                memory.get_byte(0xc000),
+                track - 1,
+                "TAC {:#04x}",
+                timer_control
+            );
+            assert!(memory.get_byte(0xc001) > 0, "TAC {:#04x}", timer_control);
+        }
+    }
+
+    #[test]
+    fn stop_until_joypad_press() {
+        let a = JoypadInput {
+            a: true,
+            ..Default::default()
+        };
+        let right = JoypadInput {
+            right: true,
+            ..Default::default()
+        };
+        // (P1 written before STOP, key pressed while stopped, wakes up)
+        let cases = [
+            (0x10, a, true),
+            (0x10, right, false),
+            (0x20, right, true),
+            (0x20, a, false),
+            (0x30, a, false),
+        ];
+        for &(p1, input, wakes_up) in cases.iter() {
+            let mut emulator = Emulator::new();
+            {
+                let mut memory = emulator.memory.borrow_mut();
+                memory.set_byte(0xff00, p1);
+                memory.set_byte(0xc000, 0x10); // STOP
+                memory.set_byte(0xc001, 0x00);
+                memory.system_counter = 0x1234;
+            }
+            emulator.cpu.registers.pc = 0xc000;
+            for _ in 0..8 {
+                emulator.tick();
+            }
+            assert!(emulator.cpu.is_stopped, "P1 {:#04x}", p1);
+            // DIV is reset and the timer, the PPU and the APU are stopped.
+            let frame_sequencer = emulator.apu.frame_sequencer_counter;
+            let blip_clock = emulator.apu.blip_clock;
+            let ly = emulator.memory.borrow().get_byte(0xff44);
+            for _ in 0..1000 {
+                emulator.tick();
+            }
+            assert_eq!(
+                emulator.memory.borrow().get_byte(0xff04),
+                0,
+                "P1 {:#04x}",
+                p1
+            );
+            assert_eq!(emulator.memory.borrow().system_counter, 0);
+            assert_eq!(emulator.memory.borrow().get_byte(0xff44), ly);
+            assert_eq!(emulator.apu.frame_sequencer_counter, frame_sequencer);
+            // the audio frame still advances.
+            assert_eq!(emulator.apu.blip_clock, blip_clock + 1000);
+
+            emulator.update_joypad_input(input);
+            emulator.tick();
+            assert_eq!(!emulator.cpu.is_stopped, wakes_up, "P1 {:#04x}", p1);
+            if wakes_up {
+                for _ in 0..4096 {
+                    emulator.tick();
+                }
+                assert!(
+                    emulator.memory.borrow().get_byte(0xff04) > 0,
+                    "P1 {:#04x}",
+                    p1
+                );
+            }
+        }
+    }
+}
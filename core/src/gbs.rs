@@ -0,0 +1,283 @@
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+// see https://ocremix.org/info/GBS_Format_Specification
+const HEADER_SIZE: usize = 0x70;
+const MAX_IMAGE_SIZE: usize = 8 * 1024 * 1024;
+
+// the player stub lives below the load address, which is at least 0x400.
+pub const DRIVER_ADDRESS: u16 = 0x200;
+const MIN_LOAD_ADDRESS: u16 = 0x400;
+
+#[derive(Debug)]
+pub enum GbsError {
+    TooSmall(usize),
+    TooLarge(usize),
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    InvalidLoadAddress(u16),
+    NoTracks,
+    InvalidTrack { track: u8, count: u8 },
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GbsError::TooSmall(n) => {
+                write!(f, "GBS file is too small to contain a header ({} bytes)", n)
+            }
+            GbsError::TooLarge(n) => write!(f, "GBS file is too large ({} bytes)", n),
+            GbsError::InvalidMagic => write!(f, "not a GBS file"),
+            GbsError::UnsupportedVersion(version) => {
+                write!(f, "unsupported GBS version {}", version)
+            }
+            GbsError::InvalidLoadAddress(address) => {
+                write!(f, "invalid GBS load address 0x{:04x}", address)
+            }
+            GbsError::NoTracks => write!(f, "GBS file has no tracks"),
+            GbsError::InvalidTrack { track, count } => {
+                write!(f, "track {} is out of range (1-{})", track, count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GbsError {}
+
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct GbsInfo {
+    #[wasm_bindgen(readonly, getter_with_clone)]
+    pub title: String,
+    #[wasm_bindgen(readonly, getter_with_clone)]
+    pub author: String,
+    #[wasm_bindgen(readonly, getter_with_clone)]
+    pub copyright: String,
+    #[wasm_bindgen(readonly)]
+    pub track_count: u8,
+    // 1-based, like the track numbers passed to Emulator::select_gbs_track.
+    #[wasm_bindgen(readonly)]
+    pub first_track: u8,
+    #[wasm_bindgen(readonly)]
+    pub load_address: u16,
+    #[wasm_bindgen(readonly)]
+    pub init_address: u16,
+    #[wasm_bindgen(readonly)]
+    pub play_address: u16,
+    #[wasm_bindgen(readonly)]
+    pub stack_pointer: u16,
+    #[wasm_bindgen(readonly)]
+    pub timer_modulo: u8,
+    #[wasm_bindgen(readonly)]
+    pub timer_control: u8,
+}
+
+impl GbsInfo {
+    // the play routine is called from the timer interrupt if TAC bit 2 is set,
+    // from the VBlank interrupt otherwise.
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & (1 << 2) != 0
+    }
+
+    pub fn check_track(&self, track: u8) -> Result<(), GbsError> {
+        if track == 0 || track > self.track_count {
+            return Err(GbsError::InvalidTrack {
+                track,
+                count: self.track_count,
+            });
+        }
+        Ok(())
+    }
+}
+
+pub struct Gbs {
+    pub info: GbsInfo,
+    // the sound data placed at its load address, with the player stub below it.
+    pub image: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn parse(data: &[u8]) -> Result<Gbs, GbsError> {
+        if data.len() < HEADER_SIZE {
+            return Err(GbsError::TooSmall(data.len()));
+        }
+        if &data[0..3] != b"GBS" {
+            return Err(GbsError::InvalidMagic);
+        }
+        if data[3] != 1 {
+            return Err(GbsError::UnsupportedVersion(data[3]));
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let text = |offset: usize| {
+            data[offset..offset + 32]
+                .iter()
+                .take_while(|&&c| c != 0)
+                .map(|&c| if c.is_ascii() { c as char } else { '?' })
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        };
+        let info = GbsInfo {
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            track_count: data[0x04],
+            first_track: data[0x05].max(1),
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0a),
+            stack_pointer: word(0x0c),
+            timer_modulo: data[0x0e],
+            timer_control: data[0x0f],
+        };
+        if !(MIN_LOAD_ADDRESS..0x8000).contains(&info.load_address) {
+            return Err(GbsError::InvalidLoadAddress(info.load_address));
+        }
+        if info.track_count == 0 {
+            return Err(GbsError::NoTracks);
+        }
+        info.check_track(info.first_track)?;
+
+        let load_address = info.load_address as usize;
+        let size = load_address + data.len() - HEADER_SIZE;
+        if size > MAX_IMAGE_SIZE {
+            return Err(GbsError::TooLarge(data.len()));
+        }
+        let mut image = vec![0; size.next_multiple_of(0x4000)];
+        image[load_address..size].copy_from_slice(&data[HEADER_SIZE..]);
+        write_driver(&mut image, &info);
+        Ok(Gbs { info, image })
+    }
+}
+
+// RST vectors jump into the sound data, the interrupt vectors call the play routine,
+// and the main loop calls the init routine then halts forever.
+fn write_driver(image: &mut [u8], info: &GbsInfo) {
+    const JP: u8 = 0xc3;
+    const CALL: u8 = 0xcd;
+    const RETI: u8 = 0xd9;
+    const EI: u8 = 0xfb;
+    const HALT: u8 = 0x76;
+    const JR: u8 = 0x18;
+
+    for rst in (0x00..=0x38).step_by(8) {
+        let [lo, hi] = (info.load_address + rst as u16).to_le_bytes();
+        image[rst..rst + 3].copy_from_slice(&[JP, lo, hi]);
+    }
+    for vector in [0x40, 0x48, 0x50, 0x58, 0x60] {
+        image[vector] = RETI;
+    }
+    let [play_lo, play_hi] = info.play_address.to_le_bytes();
+    let play_vector = if info.uses_timer() { 0x50 } else { 0x40 };
+    image[play_vector..play_vector + 4].copy_from_slice(&[CALL, play_lo, play_hi, RETI]);
+
+    let [init_lo, init_hi] = info.init_address.to_le_bytes();
+    let driver = DRIVER_ADDRESS as usize;
+    image[driver..driver + 7].copy_from_slice(&[
+        CALL, init_lo, init_hi, // the track number is passed in A
+        EI, HALT, JR, 0xfd, // back to HALT
+    ]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gbs_file(load_address: u16, timer_control: u8, code: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[0x04] = 3; // track count
+        data[0x05] = 2; // first track
+        data[0x06..0x08].copy_from_slice(&load_address.to_le_bytes());
+        data[0x08..0x0a].copy_from_slice(&load_address.to_le_bytes()); // init
+        data[0x0a..0x0c].copy_from_slice(&(load_address + 4).to_le_bytes()); // play
+        data[0x0c..0x0e].copy_from_slice(&0xdffeu16.to_le_bytes());
+        data[0x0e] = 0xc0;
+        data[0x0f] = timer_control;
+        data[0x10..0x15].copy_from_slice(b"Title");
+        data[0x30..0x38].copy_from_slice(b"Author  ");
+        data[0x50..0x54].copy_from_slice(b"1999");
+        data.extend_from_slice(code);
+        data
+    }
+
+    #[test]
+    fn parse_header() {
+        let gbs = Gbs::parse(&gbs_file(0x0400, 0x04, &[0xc9; 8])).unwrap();
+        let info = &gbs.info;
+        assert_eq!(info.title, "Title");
+        assert_eq!(info.author, "Author");
+        assert_eq!(info.copyright, "1999");
+        assert_eq!(info.track_count, 3);
+        assert_eq!(info.first_track, 2);
+        assert_eq!(info.load_address, 0x0400);
+        assert_eq!(info.init_address, 0x0400);
+        assert_eq!(info.play_address, 0x0404);
+        assert_eq!(info.stack_pointer, 0xdffe);
+        assert_eq!(info.timer_modulo, 0xc0);
+        assert!(info.uses_timer());
+        assert_eq!(gbs.image.len(), 0x4000);
+        assert_eq!(gbs.image[0x0400..0x0408], [0xc9; 8]);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_files() {
+        let too_small = gbs_file(0x0400, 0, &[])[..HEADER_SIZE - 1].to_vec();
+        let mut bad_magic = gbs_file(0x0400, 0, &[]);
+        bad_magic[0] = b'N';
+        let mut bad_version = gbs_file(0x0400, 0, &[]);
+        bad_version[3] = 2;
+        let mut no_tracks = gbs_file(0x0400, 0, &[]);
+        no_tracks[0x04] = 0;
+        let mut bad_first_track = gbs_file(0x0400, 0, &[]);
+        bad_first_track[0x05] = 4;
+        // (file, expected error)
+        let cases = [
+            (
+                too_small,
+                "GBS file is too small to contain a header (111 bytes)",
+            ),
+            (bad_magic, "not a GBS file"),
+            (bad_version, "unsupported GBS version 2"),
+            (gbs_file(0x03ff, 0, &[]), "invalid GBS load address 0x03ff"),
+            (gbs_file(0x8000, 0, &[]), "invalid GBS load address 0x8000"),
+            (no_tracks, "GBS file has no tracks"),
+            (bad_first_track, "track 4 is out of range (1-3)"),
+        ];
+        for (data, expected) in cases.iter() {
+            let err = Gbs::parse(data).err().map(|err| err.to_string());
+            assert_eq!(err.as_deref(), Some(*expected));
+        }
+    }
+
+    #[test]
+    fn driver_calls_play_from_the_requested_interrupt() {
+        // (TAC, vector calling the play routine, vector that only returns)
+        let cases = [(0x00, 0x40, 0x50), (0x04, 0x50, 0x40), (0x84, 0x50, 0x40)];
+        for &(timer_control, play_vector, other_vector) in cases.iter() {
+            let gbs = Gbs::parse(&gbs_file(0x0400, timer_control, &[])).unwrap();
+            assert_eq!(
+                gbs.image[play_vector..play_vector + 4],
+                [0xcd, 0x04, 0x04, 0xd9]
+            );
+            assert_eq!(gbs.image[other_vector], 0xd9);
+            // RST 0x38 jumps to load address + 0x38.
+            assert_eq!(gbs.image[0x38..0x3b], [0xc3, 0x38, 0x04]);
+        }
+    }
+
+    #[test]
+    fn check_track() {
+        // (track, valid) with 3 tracks
+        let cases = [(0, false), (1, true), (3, true), (4, false), (255, false)];
+        let gbs = Gbs::parse(&gbs_file(0x0400, 0, &[])).unwrap();
+        for &(track, valid) in cases.iter() {
+            assert_eq!(
+                gbs.info.check_track(track).is_ok(),
+                valid,
+                "track {}",
+                track
+            );
+        }
+    }
+}
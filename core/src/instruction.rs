@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 #[derive(Default, Debug, Clone, Copy)]
 pub enum Operand8 {
     #[default]
@@ -8,10 +10,10 @@ pub enum Operand8 {
     RegE,
     RegH,
     RegL,
-    Imm(u8),
+    Imm,
     Address(Operand16),
-    IOPortImm(u8), // (0xff00 + n)
-    IOPortC,       // (0xff00 + C)
+    IOPortImm, // (0xff00 + n)
+    IOPortC,   // (0xff00 + C)
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -22,8 +24,8 @@ pub enum Operand16 {
     RegHL,
     RegSP,
     RegAF,
-    Imm(u16),
-    AddressImm(u16), // (nn)
+    Imm,
+    AddressImm, // (nn)
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -45,13 +47,13 @@ pub enum InstKind {
     LoadDecFromA,
     LoadDecToA,
     Load16(Operand16, Operand16),
-    AddAndLoadHL(i8),
+    AddAndLoadHL,
     Push(Operand16),
     Pop(Operand16),
     Add8(Operand8),
     AddCarry8(Operand8),
     AddHL(Operand16),
-    AddSP(i8),
+    AddSP,
     Sub8(Operand8),
     SubCarry8(Operand8),
     And8(Operand8),
@@ -85,24 +87,62 @@ pub enum InstKind {
     Stop,
     DisableInterrupt,
     EnableInterrupt,
-    JumpImm(u16),
+    JumpImm,
     JumpHL,
-    JumpCondImm(JumpCond, u16),
-    JumpRel(i8),
-    JumpCondRel(JumpCond, i8),
-    CallImm(u16),
-    CallCondImm(JumpCond, u16),
+    JumpCondImm(JumpCond),
+    JumpRel,
+    JumpCondRel(JumpCond),
+    CallImm,
+    CallCondImm(JumpCond),
     Return,
     ReturnCond(JumpCond),
     ReturnEnableInterrupt,
     Restart(u16),
+    // the 0xCB prefix. the second opcode byte is fetched on the next M-cycle.
+    Prefix,
+    // not an instruction: the interrupt dispatch sequence run by the CPU.
+    Interrupt,
+}
+
+impl InstKind {
+    // the number of immediate bytes following the opcode.
+    // they are fetched one byte per M-cycle right after the opcode.
+    pub fn operand_bytes(&self) -> usize {
+        match self {
+            InstKind::Load8(Operand8::Address(Operand16::Imm), _)
+            | InstKind::Load8(_, Operand8::Address(Operand16::Imm))
+            | InstKind::Load16(_, Operand16::Imm)
+            | InstKind::Load16(Operand16::AddressImm, _)
+            | InstKind::JumpImm
+            | InstKind::JumpCondImm(_)
+            | InstKind::CallImm
+            | InstKind::CallCondImm(_) => 2,
+            InstKind::Load8(_, Operand8::Imm | Operand8::IOPortImm)
+            | InstKind::Load8(Operand8::IOPortImm, _)
+            | InstKind::Add8(Operand8::Imm)
+            | InstKind::AddCarry8(Operand8::Imm)
+            | InstKind::Sub8(Operand8::Imm)
+            | InstKind::SubCarry8(Operand8::Imm)
+            | InstKind::And8(Operand8::Imm)
+            | InstKind::Or8(Operand8::Imm)
+            | InstKind::Xor8(Operand8::Imm)
+            | InstKind::Compare8(Operand8::Imm)
+            | InstKind::AddSP
+            | InstKind::AddAndLoadHL
+            | InstKind::JumpRel
+            | InstKind::JumpCondRel(_) => 1,
+            _ => 0,
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct Inst {
+    #[allow(dead_code)]
     pub opcode: usize,
     pub kind: InstKind,
     pub clocks: usize,
+    #[allow(dead_code)]
     pub length: usize,
 }
 
@@ -157,7 +197,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
             let clocks = if i == 6 { 12 } else { 8 };
             inst_table[opcode] = Some(Inst {
                 opcode,
-                kind: InstKind::Load8(*op1, Operand8::Imm(0)),
+                kind: InstKind::Load8(*op1, Operand8::Imm),
                 clocks,
                 length: 2,
             });
@@ -186,7 +226,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `LD A, (nn)`
         inst_table[0xfa] = Some(Inst {
             opcode: 0xfa,
-            kind: InstKind::Load8(Operand8::RegA, Operand8::Address(Operand16::Imm(0))),
+            kind: InstKind::Load8(Operand8::RegA, Operand8::Address(Operand16::Imm)),
             clocks: 16,
             length: 3,
         });
@@ -207,21 +247,21 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `LD (nn), A`
         inst_table[0xea] = Some(Inst {
             opcode: 0xea,
-            kind: InstKind::Load8(Operand8::Address(Operand16::Imm(0)), Operand8::RegA),
+            kind: InstKind::Load8(Operand8::Address(Operand16::Imm), Operand8::RegA),
             clocks: 16,
             length: 3,
         });
         // `LD A, (0xff00 + n)`
         inst_table[0xf0] = Some(Inst {
             opcode: 0xf0,
-            kind: InstKind::Load8(Operand8::RegA, Operand8::IOPortImm(0)),
+            kind: InstKind::Load8(Operand8::RegA, Operand8::IOPortImm),
             clocks: 12,
             length: 2,
         });
         // `LD (0xff00 + n), A`
         inst_table[0xe0] = Some(Inst {
             opcode: 0xe0,
-            kind: InstKind::Load8(Operand8::IOPortImm(0), Operand8::RegA),
+            kind: InstKind::Load8(Operand8::IOPortImm, Operand8::RegA),
             clocks: 12,
             length: 2,
         });
@@ -275,7 +315,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
             let opcode = 1 + (i << 4);
             inst_table[opcode] = Some(Inst {
                 opcode,
-                kind: InstKind::Load16(*op, Operand16::Imm(0)),
+                kind: InstKind::Load16(*op, Operand16::Imm),
                 clocks: 12,
                 length: 3,
             })
@@ -283,7 +323,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `LD (nn), SP`
         inst_table[0x08] = Some(Inst {
             opcode: 0x08,
-            kind: InstKind::Load16(Operand16::AddressImm(0), Operand16::RegSP),
+            kind: InstKind::Load16(Operand16::AddressImm, Operand16::RegSP),
             clocks: 20,
             length: 3,
         });
@@ -351,7 +391,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `ADD A, d`
         inst_table[0xc6] = Some(Inst {
             opcode: 0xc6,
-            kind: InstKind::Add8(Operand8::Imm(0)),
+            kind: InstKind::Add8(Operand8::Imm),
             clocks: 8,
             length: 2,
         });
@@ -369,7 +409,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `ADC A, d`
         inst_table[0xce] = Some(Inst {
             opcode: 0xce,
-            kind: InstKind::AddCarry8(Operand8::Imm(0)),
+            kind: InstKind::AddCarry8(Operand8::Imm),
             clocks: 8,
             length: 2,
         });
@@ -387,7 +427,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `SUB A, d`
         inst_table[0xd6] = Some(Inst {
             opcode: 0xd6,
-            kind: InstKind::Sub8(Operand8::Imm(0)),
+            kind: InstKind::Sub8(Operand8::Imm),
             clocks: 8,
             length: 2,
         });
@@ -405,7 +445,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `SBC A, d`
         inst_table[0xde] = Some(Inst {
             opcode: 0xde,
-            kind: InstKind::SubCarry8(Operand8::Imm(0)),
+            kind: InstKind::SubCarry8(Operand8::Imm),
             clocks: 8,
             length: 2,
         });
@@ -423,7 +463,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `AND A, d`
         inst_table[0xe6] = Some(Inst {
             opcode: 0xe6,
-            kind: InstKind::And8(Operand8::Imm(0)),
+            kind: InstKind::And8(Operand8::Imm),
             clocks: 8,
             length: 2,
         });
@@ -441,7 +481,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `XOR A, d`
         inst_table[0xee] = Some(Inst {
             opcode: 0xee,
-            kind: InstKind::Xor8(Operand8::Imm(0)),
+            kind: InstKind::Xor8(Operand8::Imm),
             clocks: 8,
             length: 2,
         });
@@ -459,7 +499,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `OR A, d`
         inst_table[0xf6] = Some(Inst {
             opcode: 0xf6,
-            kind: InstKind::Or8(Operand8::Imm(0)),
+            kind: InstKind::Or8(Operand8::Imm),
             clocks: 8,
             length: 2,
         });
@@ -477,7 +517,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `CP A, d`
         inst_table[0xfe] = Some(Inst {
             opcode: 0xfe,
-            kind: InstKind::Compare8(Operand8::Imm(0)),
+            kind: InstKind::Compare8(Operand8::Imm),
             clocks: 8,
             length: 2,
         });
@@ -553,14 +593,14 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `ADD SP, dd`
         inst_table[0xe8] = Some(Inst {
             opcode: 0xe8,
-            kind: InstKind::AddSP(0),
+            kind: InstKind::AddSP,
             clocks: 16,
             length: 2,
         });
         // `LD HL, SP + dd`
         inst_table[0xf8] = Some(Inst {
             opcode: 0xf8,
-            kind: InstKind::AddAndLoadHL(0),
+            kind: InstKind::AddAndLoadHL,
             clocks: 12,
             length: 2,
         });
@@ -626,6 +666,13 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
             clocks: 4,
             length: 1,
         });
+        // the prefix of the `CB xx` instructions
+        inst_table[0xcb] = Some(Inst {
+            opcode: 0xcb,
+            kind: InstKind::Prefix,
+            clocks: 8,
+            length: 2,
+        });
         // `STOP`
         inst_table[0x10] = Some(Inst {
             opcode: 0x10,
@@ -653,7 +700,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `JP nn`
         inst_table[0xc3] = Some(Inst {
             opcode: 0xc3,
-            kind: InstKind::JumpImm(0),
+            kind: InstKind::JumpImm,
             clocks: 16,
             length: 3,
         });
@@ -669,7 +716,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
             let opcode = 0xc2 + (i << 3);
             inst_table[opcode] = Some(Inst {
                 opcode,
-                kind: InstKind::JumpCondImm(*cond, 0),
+                kind: InstKind::JumpCondImm(*cond),
                 clocks: 12,
                 length: 3,
             });
@@ -677,7 +724,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `JR PC+dd`
         inst_table[0x18] = Some(Inst {
             opcode: 0x18,
-            kind: InstKind::JumpRel(0),
+            kind: InstKind::JumpRel,
             clocks: 12,
             length: 2,
         });
@@ -686,7 +733,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
             let opcode = 0x20 + (i << 3);
             inst_table[opcode] = Some(Inst {
                 opcode,
-                kind: InstKind::JumpCondRel(*cond, 0),
+                kind: InstKind::JumpCondRel(*cond),
                 clocks: 8,
                 length: 2,
             });
@@ -694,7 +741,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
         // `CALL nn`
         inst_table[0xcd] = Some(Inst {
             opcode: 0xcd,
-            kind: InstKind::CallImm(0),
+            kind: InstKind::CallImm,
             clocks: 24,
             length: 3,
         });
@@ -703,7 +750,7 @@ pub fn generate_main_inst_table() -> Vec<Option<Inst>> {
             let opcode = 0xc4 + (i << 3);
             inst_table[opcode] = Some(Inst {
                 opcode,
-                kind: InstKind::CallCondImm(*cond, 0),
+                kind: InstKind::CallCondImm(*cond),
                 clocks: 12,
                 length: 3,
             });
@@ -885,3 +932,345 @@ pub fn generate_sub_inst_table() -> Vec<Inst> {
     }
     inst_table
 }
+
+// Encoding of decoded instructions for save states.
+// An instruction in flight can be synthesized by the CPU (e.g. interrupt dispatch),
+// so the decoded form is stored rather than the opcode.
+// immediates fetched so far are kept in the CPU state.
+
+fn write_operand16(writer: &mut StateWriter, op: Operand16) {
+    match op {
+        Operand16::RegBC => writer.write_u8(0),
+        Operand16::RegDE => writer.write_u8(1),
+        Operand16::RegHL => writer.write_u8(2),
+        Operand16::RegSP => writer.write_u8(3),
+        Operand16::RegAF => writer.write_u8(4),
+        Operand16::Imm => writer.write_u8(5),
+        Operand16::AddressImm => writer.write_u8(6),
+    }
+}
+
+fn read_operand16(reader: &mut StateReader) -> Result<Operand16, StateError> {
+    Ok(match reader.read_u8()? {
+        0 => Operand16::RegBC,
+        1 => Operand16::RegDE,
+        2 => Operand16::RegHL,
+        3 => Operand16::RegSP,
+        4 => Operand16::RegAF,
+        5 => Operand16::Imm,
+        6 => Operand16::AddressImm,
+        _ => return Err(StateError::InvalidData("16-bit operand")),
+    })
+}
+
+fn write_operand8(writer: &mut StateWriter, op: Operand8) {
+    match op {
+        Operand8::RegA => writer.write_u8(0),
+        Operand8::RegB => writer.write_u8(1),
+        Operand8::RegC => writer.write_u8(2),
+        Operand8::RegD => writer.write_u8(3),
+        Operand8::RegE => writer.write_u8(4),
+        Operand8::RegH => writer.write_u8(5),
+        Operand8::RegL => writer.write_u8(6),
+        Operand8::Imm => writer.write_u8(7),
+        Operand8::Address(op16) => {
+            writer.write_u8(8);
+            write_operand16(writer, op16);
+        }
+        Operand8::IOPortImm => writer.write_u8(9),
+        Operand8::IOPortC => writer.write_u8(10),
+    }
+}
+
+fn read_operand8(reader: &mut StateReader) -> Result<Operand8, StateError> {
+    Ok(match reader.read_u8()? {
+        0 => Operand8::RegA,
+        1 => Operand8::RegB,
+        2 => Operand8::RegC,
+        3 => Operand8::RegD,
+        4 => Operand8::RegE,
+        5 => Operand8::RegH,
+        6 => Operand8::RegL,
+        7 => Operand8::Imm,
+        8 => Operand8::Address(read_operand16(reader)?),
+        9 => Operand8::IOPortImm,
+        10 => Operand8::IOPortC,
+        _ => return Err(StateError::InvalidData("8-bit operand")),
+    })
+}
+
+fn write_jump_cond(writer: &mut StateWriter, cond: JumpCond) {
+    writer.write_u8(match cond {
+        JumpCond::NZ => 0,
+        JumpCond::Z => 1,
+        JumpCond::NC => 2,
+        JumpCond::C => 3,
+    });
+}
+
+fn read_jump_cond(reader: &mut StateReader) -> Result<JumpCond, StateError> {
+    Ok(match reader.read_u8()? {
+        0 => JumpCond::NZ,
+        1 => JumpCond::Z,
+        2 => JumpCond::NC,
+        3 => JumpCond::C,
+        _ => return Err(StateError::InvalidData("jump condition")),
+    })
+}
+
+fn read_bit(reader: &mut StateReader) -> Result<usize, StateError> {
+    match reader.read_u8()? {
+        n @ 0..=7 => Ok(n as usize),
+        _ => Err(StateError::InvalidData("bit number")),
+    }
+}
+
+fn write_inst_kind(writer: &mut StateWriter, inst: InstKind) {
+    match inst {
+        InstKind::Nop => writer.write_u8(0),
+        InstKind::Load8(dst, src) => {
+            writer.write_u8(1);
+            write_operand8(writer, dst);
+            write_operand8(writer, src);
+        }
+        InstKind::LoadIncFromA => writer.write_u8(2),
+        InstKind::LoadIncToA => writer.write_u8(3),
+        InstKind::LoadDecFromA => writer.write_u8(4),
+        InstKind::LoadDecToA => writer.write_u8(5),
+        InstKind::Load16(dst, src) => {
+            writer.write_u8(6);
+            write_operand16(writer, dst);
+            write_operand16(writer, src);
+        }
+        InstKind::AddAndLoadHL => writer.write_u8(7),
+        InstKind::Push(op) => {
+            writer.write_u8(8);
+            write_operand16(writer, op);
+        }
+        InstKind::Pop(op) => {
+            writer.write_u8(9);
+            write_operand16(writer, op);
+        }
+        InstKind::Add8(op) => {
+            writer.write_u8(10);
+            write_operand8(writer, op);
+        }
+        InstKind::AddCarry8(op) => {
+            writer.write_u8(11);
+            write_operand8(writer, op);
+        }
+        InstKind::AddHL(op) => {
+            writer.write_u8(12);
+            write_operand16(writer, op);
+        }
+        InstKind::AddSP => writer.write_u8(13),
+        InstKind::Sub8(op) => {
+            writer.write_u8(14);
+            write_operand8(writer, op);
+        }
+        InstKind::SubCarry8(op) => {
+            writer.write_u8(15);
+            write_operand8(writer, op);
+        }
+        InstKind::And8(op) => {
+            writer.write_u8(16);
+            write_operand8(writer, op);
+        }
+        InstKind::Or8(op) => {
+            writer.write_u8(17);
+            write_operand8(writer, op);
+        }
+        InstKind::Xor8(op) => {
+            writer.write_u8(18);
+            write_operand8(writer, op);
+        }
+        InstKind::Compare8(op) => {
+            writer.write_u8(19);
+            write_operand8(writer, op);
+        }
+        InstKind::Inc8(op) => {
+            writer.write_u8(20);
+            write_operand8(writer, op);
+        }
+        InstKind::Dec8(op) => {
+            writer.write_u8(21);
+            write_operand8(writer, op);
+        }
+        InstKind::Inc16(op) => {
+            writer.write_u8(22);
+            write_operand16(writer, op);
+        }
+        InstKind::Dec16(op) => {
+            writer.write_u8(23);
+            write_operand16(writer, op);
+        }
+        InstKind::DecimalAdjustA => writer.write_u8(24),
+        InstKind::ComplementA => writer.write_u8(25),
+        InstKind::RotateALeft => writer.write_u8(26),
+        InstKind::RotateALeftCarry => writer.write_u8(27),
+        InstKind::RotateLeft(op) => {
+            writer.write_u8(28);
+            write_operand8(writer, op);
+        }
+        InstKind::RotateLeftCarry(op) => {
+            writer.write_u8(29);
+            write_operand8(writer, op);
+        }
+        InstKind::RotateARight => writer.write_u8(30),
+        InstKind::RotateARightCarry => writer.write_u8(31),
+        InstKind::RotateRight(op) => {
+            writer.write_u8(32);
+            write_operand8(writer, op);
+        }
+        InstKind::RotateRightCarry(op) => {
+            writer.write_u8(33);
+            write_operand8(writer, op);
+        }
+        InstKind::ShiftLeftArithmetic(op) => {
+            writer.write_u8(34);
+            write_operand8(writer, op);
+        }
+        InstKind::ShiftRightArithmetic(op) => {
+            writer.write_u8(35);
+            write_operand8(writer, op);
+        }
+        InstKind::ShiftRightLogical(op) => {
+            writer.write_u8(36);
+            write_operand8(writer, op);
+        }
+        InstKind::Swap(op) => {
+            writer.write_u8(37);
+            write_operand8(writer, op);
+        }
+        InstKind::TestBit(n, op) => {
+            writer.write_u8(38);
+            writer.write_u8(n as u8);
+            write_operand8(writer, op);
+        }
+        InstKind::SetBit(n, op) => {
+            writer.write_u8(39);
+            writer.write_u8(n as u8);
+            write_operand8(writer, op);
+        }
+        InstKind::ResetBit(n, op) => {
+            writer.write_u8(40);
+            writer.write_u8(n as u8);
+            write_operand8(writer, op);
+        }
+        InstKind::ComplementCarryFlag => writer.write_u8(41),
+        InstKind::SetCarryFlag => writer.write_u8(42),
+        InstKind::Halt => writer.write_u8(43),
+        InstKind::Stop => writer.write_u8(44),
+        InstKind::DisableInterrupt => writer.write_u8(45),
+        InstKind::EnableInterrupt => writer.write_u8(46),
+        InstKind::JumpImm => writer.write_u8(47),
+        InstKind::JumpHL => writer.write_u8(48),
+        InstKind::JumpCondImm(cond) => {
+            writer.write_u8(49);
+            write_jump_cond(writer, cond);
+        }
+        InstKind::JumpRel => writer.write_u8(50),
+        InstKind::JumpCondRel(cond) => {
+            writer.write_u8(51);
+            write_jump_cond(writer, cond);
+        }
+        InstKind::CallImm => writer.write_u8(52),
+        InstKind::CallCondImm(cond) => {
+            writer.write_u8(53);
+            write_jump_cond(writer, cond);
+        }
+        InstKind::Return => writer.write_u8(54),
+        InstKind::ReturnCond(cond) => {
+            writer.write_u8(55);
+            write_jump_cond(writer, cond);
+        }
+        InstKind::ReturnEnableInterrupt => writer.write_u8(56),
+        InstKind::Restart(addr) => {
+            writer.write_u8(57);
+            writer.write_u16(addr);
+        }
+        InstKind::Interrupt => writer.write_u8(58),
+        InstKind::Prefix => writer.write_u8(59),
+    }
+}
+
+fn read_inst_kind(reader: &mut StateReader) -> Result<InstKind, StateError> {
+    Ok(match reader.read_u8()? {
+        0 => InstKind::Nop,
+        1 => InstKind::Load8(read_operand8(reader)?, read_operand8(reader)?),
+        2 => InstKind::LoadIncFromA,
+        3 => InstKind::LoadIncToA,
+        4 => InstKind::LoadDecFromA,
+        5 => InstKind::LoadDecToA,
+        6 => InstKind::Load16(read_operand16(reader)?, read_operand16(reader)?),
+        7 => InstKind::AddAndLoadHL,
+        8 => InstKind::Push(read_operand16(reader)?),
+        9 => InstKind::Pop(read_operand16(reader)?),
+        10 => InstKind::Add8(read_operand8(reader)?),
+        11 => InstKind::AddCarry8(read_operand8(reader)?),
+        12 => InstKind::AddHL(read_operand16(reader)?),
+        13 => InstKind::AddSP,
+        14 => InstKind::Sub8(read_operand8(reader)?),
+        15 => InstKind::SubCarry8(read_operand8(reader)?),
+        16 => InstKind::And8(read_operand8(reader)?),
+        17 => InstKind::Or8(read_operand8(reader)?),
+        18 => InstKind::Xor8(read_operand8(reader)?),
+        19 => InstKind::Compare8(read_operand8(reader)?),
+        20 => InstKind::Inc8(read_operand8(reader)?),
+        21 => InstKind::Dec8(read_operand8(reader)?),
+        22 => InstKind::Inc16(read_operand16(reader)?),
+        23 => InstKind::Dec16(read_operand16(reader)?),
+        24 => InstKind::DecimalAdjustA,
+        25 => InstKind::ComplementA,
+        26 => InstKind::RotateALeft,
+        27 => InstKind::RotateALeftCarry,
+        28 => InstKind::RotateLeft(read_operand8(reader)?),
+        29 => InstKind::RotateLeftCarry(read_operand8(reader)?),
+        30 => InstKind::RotateARight,
+        31 => InstKind::RotateARightCarry,
+        32 => InstKind::RotateRight(read_operand8(reader)?),
+        33 => InstKind::RotateRightCarry(read_operand8(reader)?),
+        34 => InstKind::ShiftLeftArithmetic(read_operand8(reader)?),
+        35 => InstKind::ShiftRightArithmetic(read_operand8(reader)?),
+        36 => InstKind::ShiftRightLogical(read_operand8(reader)?),
+        37 => InstKind::Swap(read_operand8(reader)?),
+        38 => InstKind::TestBit(read_bit(reader)?, read_operand8(reader)?),
+        39 => InstKind::SetBit(read_bit(reader)?, read_operand8(reader)?),
+        40 => InstKind::ResetBit(read_bit(reader)?, read_operand8(reader)?),
+        41 => InstKind::ComplementCarryFlag,
+        42 => InstKind::SetCarryFlag,
+        43 => InstKind::Halt,
+        44 => InstKind::Stop,
+        45 => InstKind::DisableInterrupt,
+        46 => InstKind::EnableInterrupt,
+        47 => InstKind::JumpImm,
+        48 => InstKind::JumpHL,
+        49 => InstKind::JumpCondImm(read_jump_cond(reader)?),
+        50 => InstKind::JumpRel,
+        51 => InstKind::JumpCondRel(read_jump_cond(reader)?),
+        52 => InstKind::CallImm,
+        53 => InstKind::CallCondImm(read_jump_cond(reader)?),
+        54 => InstKind::Return,
+        55 => InstKind::ReturnCond(read_jump_cond(reader)?),
+        56 => InstKind::ReturnEnableInterrupt,
+        57 => InstKind::Restart(reader.read_u16()?),
+        58 => InstKind::Interrupt,
+        59 => InstKind::Prefix,
+        _ => return Err(StateError::InvalidData("instruction")),
+    })
+}
+
+pub fn write_option_inst_kind(writer: &mut StateWriter, inst: Option<InstKind>) {
+    writer.write_bool(inst.is_some());
+    if let Some(inst) = inst {
+        write_inst_kind(writer, inst);
+    }
+}
+
+pub fn read_option_inst_kind(reader: &mut StateReader) -> Result<Option<InstKind>, StateError> {
+    if reader.read_bool()? {
+        Ok(Some(read_inst_kind(reader)?))
+    } else {
+        Ok(None)
+    }
+}
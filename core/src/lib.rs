@@ -1,7 +1,15 @@
+#![allow(clippy::upper_case_acronyms)]
+
 mod apu;
+mod blip_buffer;
+mod cartridge;
 mod cpu;
 mod emulator;
+mod gbs;
 mod instruction;
+mod mapper;
 mod memory;
 mod ppu;
+mod state;
 mod timer;
+mod wav;
@@ -0,0 +1,322 @@
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rom_only;
+pub mod rtc;
+
+use crate::mapper::mbc1::Mbc1;
+use crate::mapper::mbc2::Mbc2;
+use crate::mapper::mbc3::Mbc3;
+use crate::mapper::mbc5::Mbc5;
+use crate::mapper::rom_only::RomOnly;
+use crate::mapper::rtc::Rtc;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use std::fmt;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug)]
+pub enum SavedataError {
+    TooLarge { capacity: usize, size: usize },
+}
+
+impl fmt::Display for SavedataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SavedataError::TooLarge { capacity, size } => write!(
+                f,
+                "save data is too large for the cartridge RAM ({} bytes, expected at most {})",
+                size, capacity
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SavedataError {}
+
+// A cartridge with its memory bank controller (MBC).
+// Each implementation owns the ROM, the external RAM and its banking registers.
+// see https://gbdev.io/pandocs/MBCs.html
+pub trait Mapper: Snapshot {
+    // 0x0000-0x7FFF
+    fn read_rom(&self, address: u16) -> u8;
+    // writes to the ROM area are used to control the MBC.
+    fn write_rom(&mut self, address: u16, value: u8);
+    // 0xA000-0xBFFF
+    fn read_ram(&self, address: u16) -> u8;
+    fn write_ram(&mut self, address: u16, value: u8);
+
+    fn rom(&self) -> &[u8];
+
+    // called once per clock.
+    fn tick(&mut self) {}
+
+    // contents of the battery-backed RAM.
+    fn savedata(&self) -> Vec<u8>;
+    fn load_savedata(&mut self, savedata: &[u8]) -> Result<(), SavedataError>;
+
+    fn rtc(&self) -> Option<&Rtc> {
+        None
+    }
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+}
+
+pub fn is_supported(cart_type: u8) -> bool {
+    matches!(
+        cart_type,
+        0x00 | 0x01..=0x03 | 0x05 | 0x06 | 0x08 | 0x09 | 0x0f..=0x13 | 0x19..=0x1e
+    )
+}
+
+// choose the mapper from the cartridge type at 0x147 of the header.
+// unsupported cartridge types are treated as ROM only.
+pub fn from_rom(rom: Vec<u8>) -> Box<dyn Mapper> {
+    let cart_type = rom.get(0x147).copied().unwrap_or(0);
+    let ram_size = ram_size(rom.get(0x149).copied().unwrap_or(0));
+    match cart_type {
+        0x01..=0x03 => Box::new(Mbc1::new(rom, ram_size)),
+        0x05 | 0x06 => Box::new(Mbc2::new(rom)),
+        0x0f..=0x13 => {
+            let has_rtc = cart_type == 0x0f || cart_type == 0x10;
+            Box::new(Mbc3::new(rom, ram_size, has_rtc))
+        }
+        0x19..=0x1e => {
+            let has_rumble = cart_type >= 0x1c;
+            Box::new(Mbc5::new(rom, ram_size, has_rumble))
+        }
+        _ => Box::new(RomOnly::new(rom, ram_size)),
+    }
+}
+
+// GBS files switch ROM banks by writing to 0x2000-0x3FFF and may use 0xA000-0xBFFF as RAM,
+// which an MBC5 with 8KB of RAM enabled from the start covers.
+pub fn for_gbs(image: Vec<u8>) -> Box<dyn Mapper> {
+    let mut mapper = Mbc5::new(image, 8 * 1024, false);
+    mapper.write_rom(0x0000, 0x0a);
+    Box::new(mapper)
+}
+
+// the size of the external RAM in bytes, as declared at 0x149 of the header.
+pub fn ram_size(code: u8) -> usize {
+    match code {
+        0x02 => 8 * 1024,
+        0x03 => 32 * 1024,
+        0x04 => 128 * 1024,
+        0x05 => 64 * 1024,
+        _ => 0,
+    }
+}
+
+// bank numbers beyond the ROM size wrap around, as the unused upper bits
+// of the bank register are not connected.
+fn read_rom_bank(rom: &[u8], bank: usize, address: u16) -> u8 {
+    let bank_count = rom.len().div_ceil(ROM_BANK_SIZE).max(1).next_power_of_two();
+    let offset = (bank & (bank_count - 1)) * ROM_BANK_SIZE + (address as usize & 0x3fff);
+    rom.get(offset).copied().unwrap_or(0xff)
+}
+
+// same as ROM banks, RAM banks beyond the RAM size are mirrored.
+fn ram_offset(ram: &[u8], bank: usize, address: u16) -> usize {
+    (bank * RAM_BANK_SIZE + (address as usize & 0x1fff)) % ram.len()
+}
+
+fn read_ram_bank(ram: &[u8], bank: usize, address: u16) -> u8 {
+    if ram.is_empty() {
+        // there is nothing to drive the bus if the cartridge has no RAM.
+        return 0xff;
+    }
+    ram[ram_offset(ram, bank, address)]
+}
+
+fn write_ram_bank(ram: &mut [u8], bank: usize, address: u16, value: u8) {
+    if ram.is_empty() {
+        return;
+    }
+    let offset = ram_offset(ram, bank, address);
+    ram[offset] = value;
+}
+
+// a save file shorter than the RAM is accepted, the rest of the RAM is left untouched.
+fn load_ram(ram: &mut [u8], savedata: &[u8]) -> Result<(), SavedataError> {
+    if savedata.len() > ram.len() {
+        return Err(SavedataError::TooLarge {
+            capacity: ram.len(),
+            size: savedata.len(),
+        });
+    }
+    ram[0..savedata.len()].copy_from_slice(savedata);
+    Ok(())
+}
+
+fn save_ram_state(ram: &[u8], writer: &mut StateWriter) {
+    writer.write_usize(ram.len());
+    writer.write_bytes(ram);
+}
+
+fn load_ram_state(ram: &mut [u8], reader: &mut StateReader) -> Result<(), StateError> {
+    if reader.read_usize()? != ram.len() {
+        return Err(StateError::CartridgeMismatch);
+    }
+    reader.read_bytes(ram)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (address, value) written to the MBC registers in order.
+    type Writes = &'static [(u16, u8)];
+
+    // each ROM bank starts with its own bank number (little endian).
+    fn cartridge(cart_type: u8, rom_banks: usize, ram_code: u8) -> Box<dyn Mapper> {
+        let mut rom = vec![0; rom_banks * ROM_BANK_SIZE];
+        for bank in 0..rom_banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom[0x147] = cart_type;
+        rom[0x149] = ram_code;
+        from_rom(rom)
+    }
+
+    fn read_bank(mapper: &dyn Mapper, address: u16) -> usize {
+        mapper.read_rom(address) as usize | (mapper.read_rom(address + 1) as usize) << 8
+    }
+
+    #[test]
+    fn rom_banking() {
+        // (cartridge type, ROM banks, writes, address, expected bank)
+        let cases: [(u8, usize, Writes, u16, usize); 20] = [
+            // MBC1
+            (0x01, 128, &[], 0x4000, 1),
+            (0x01, 128, &[(0x2000, 0x00)], 0x4000, 1),
+            (0x01, 128, &[(0x2000, 0x05)], 0x4000, 5),
+            (0x01, 128, &[(0x2000, 0x25)], 0x4000, 5),
+            (0x01, 128, &[(0x2000, 0x02), (0x4000, 0x01)], 0x4000, 0x22),
+            (0x01, 128, &[(0x4000, 0x01), (0x2000, 0x00)], 0x4000, 0x21),
+            (0x01, 128, &[(0x4000, 0x01)], 0x0000, 0),
+            (0x01, 128, &[(0x4000, 0x01), (0x6000, 0x01)], 0x0000, 0x20),
+            (0x01, 8, &[(0x2000, 0x09)], 0x4000, 1), // wraps around the ROM size
+            // MBC2
+            (0x05, 16, &[(0x2100, 0x03)], 0x4000, 3),
+            (0x05, 16, &[(0x2000, 0x03)], 0x4000, 1),
+            (0x05, 16, &[(0x2100, 0x00)], 0x4000, 1),
+            // MBC3
+            (0x11, 128, &[(0x2000, 0x7f)], 0x4000, 0x7f),
+            (0x11, 128, &[(0x2000, 0x00)], 0x4000, 1),
+            (0x11, 128, &[(0x2000, 0x81)], 0x4000, 1),
+            (0x11, 128, &[(0x2000, 0x05)], 0x0000, 0),
+            // MBC5
+            (0x19, 512, &[(0x2000, 0x00)], 0x4000, 0),
+            (0x19, 512, &[(0x2000, 0xff)], 0x4000, 0xff),
+            (0x19, 512, &[(0x2000, 0x01), (0x3000, 0x01)], 0x4000, 0x101),
+            (0x19, 512, &[(0x3000, 0x01), (0x2000, 0x00)], 0x0000, 0),
+        ];
+        for (i, &(cart_type, rom_banks, writes, address, expected)) in cases.iter().enumerate() {
+            let mut mapper = cartridge(cart_type, rom_banks, 0);
+            for &(address, value) in writes.iter() {
+                mapper.write_rom(address, value);
+            }
+            assert_eq!(read_bank(mapper.as_ref(), address), expected, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn ram_banking() {
+        // (cartridge type, RAM size code, writes, offset in the save data, expected value)
+        // 0x5a is written to 0xA123 after the writes.
+        let cases: [(u8, u8, Writes, usize, u8); 8] = [
+            (0x03, 0x03, &[(0x0000, 0x0a), (0x4000, 0x02)], 0x0123, 0x5a),
+            (
+                0x03,
+                0x03,
+                &[(0x0000, 0x0a), (0x4000, 0x02), (0x6000, 0x01)],
+                0x4123,
+                0x5a,
+            ),
+            (0x03, 0x03, &[(0x0000, 0x1a)], 0x0123, 0x5a), // only the lower 4 bits count
+            (0x03, 0x03, &[(0x0000, 0x0a), (0x0000, 0x00)], 0x0123, 0x00),
+            (0x13, 0x03, &[(0x0000, 0x0a), (0x4000, 0x03)], 0x6123, 0x5a),
+            (0x1b, 0x04, &[(0x0000, 0x0a), (0x4000, 0x0f)], 0x1e123, 0x5a),
+            (0x1b, 0x04, &[(0x0000, 0x1a)], 0x0123, 0x00), // MBC5 needs exactly 0x0A
+            (0x1e, 0x03, &[(0x0000, 0x0a), (0x4000, 0x0b)], 0x6123, 0x5a), // bit 3 is rumble
+        ];
+        for (i, &(cart_type, ram_code, writes, offset, expected)) in cases.iter().enumerate() {
+            let mut mapper = cartridge(cart_type, 2, ram_code);
+            for &(address, value) in writes.iter() {
+                mapper.write_rom(address, value);
+            }
+            mapper.write_ram(0xa123, 0x5a);
+            assert_eq!(mapper.savedata()[offset], expected, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn load_savedata_checks_the_size() {
+        // (cartridge type, RAM size code, save file size, accepted)
+        let cases = [
+            (0x03, 0x02, 0x2000, true),
+            (0x03, 0x02, 0x1000, true),
+            (0x03, 0x02, 0x2001, false),
+            (0x03, 0x00, 0x0001, false),
+            (0x06, 0x00, 0x0200, true),
+            (0x06, 0x00, 0x0400, false),
+            (0x13, 0x03, 0x8000, true),
+            (0x13, 0x03, 0x10000, false),
+            (0x1b, 0x04, 0x20000, true),
+            (0x1b, 0x04, 0x20001, false),
+        ];
+        for &(cart_type, ram_code, size, accepted) in cases.iter() {
+            let mut mapper = cartridge(cart_type, 4, ram_code);
+            let result = mapper.load_savedata(&vec![0x05; size]);
+            assert_eq!(
+                result.is_ok(),
+                accepted,
+                "{:#04x}, {} bytes",
+                cart_type,
+                size
+            );
+            if accepted {
+                assert_eq!(mapper.savedata()[..size], vec![0x05; size][..]);
+            }
+        }
+    }
+
+    #[test]
+    fn rtc_latch_and_savedata_round_trip() {
+        // (register, value written, value read back after latching)
+        let cases = [
+            (0x08, 59, 59),
+            (0x09, 0x7f, 0x3f),
+            (0x0a, 23, 23),
+            (0x0b, 0xff, 0xff),
+            (0x0c, 0xc1, 0xc1),
+        ];
+        let mut mapper = cartridge(0x10, 2, 0x03);
+        mapper.write_rom(0x0000, 0x0a);
+        for &(register, value, _) in cases.iter() {
+            mapper.write_rom(0x4000, register);
+            mapper.write_ram(0xa000, value);
+        }
+        mapper.write_rom(0x6000, 0x00);
+        mapper.write_rom(0x6000, 0x01);
+        for &(register, _, expected) in cases.iter() {
+            mapper.write_rom(0x4000, register);
+            assert_eq!(mapper.read_ram(0xa000), expected, "{:#04x}", register);
+        }
+
+        let footer = mapper.rtc().unwrap().footer(1234567890);
+        let mut restored = cartridge(0x10, 2, 0x03);
+        restored.rtc_mut().unwrap().load_footer(&footer);
+        restored.write_rom(0x0000, 0x0a);
+        for &(register, _, expected) in cases.iter() {
+            restored.write_rom(0x4000, register);
+            assert_eq!(restored.read_ram(0xa000), expected, "{:#04x}", register);
+        }
+        assert_eq!(restored.rtc().unwrap().footer(1234567890), footer);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::mapper::{self, Mapper, SavedataError};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// up to 2 MiB ROM and/or 32 KiB RAM.
+// see https://gbdev.io/pandocs/MBC1.html
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    bank1: u8, // lower 5 bits of the ROM bank number
+    bank2: u8, // upper 2 bits of the ROM bank number or the RAM bank number
+    banking_mode: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        Mbc1 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enable: false,
+            bank1: 1,
+            bank2: 0,
+            banking_mode: false,
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.banking_mode {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3fff => {
+                // in the advanced banking mode, BANK2 also applies to 0x0000-0x3FFF.
+                let bank = if self.banking_mode {
+                    (self.bank2 as usize) << 5
+                } else {
+                    0
+                };
+                mapper::read_rom_bank(&self.rom, bank, address)
+            }
+            _ => {
+                let bank = ((self.bank2 as usize) << 5) | self.bank1 as usize;
+                mapper::read_rom_bank(&self.rom, bank, address)
+            }
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => {
+                // any value with 0xA in the lower 4 bits enables the RAM.
+                self.ram_enable = (value & 0xf) == 0xa;
+            }
+            0x2000..=0x3fff => {
+                // bank 0 cannot be selected, it is translated to bank 1.
+                self.bank1 = (value & 0x1f).max(1);
+            }
+            0x4000..=0x5fff => self.bank2 = value & 0x3,
+            _ => self.banking_mode = value & 1 != 0,
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+        mapper::read_ram_bank(&self.ram, self.ram_bank(), address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enable {
+            return;
+        }
+        let bank = self.ram_bank();
+        mapper::write_ram_bank(&mut self.ram, bank, address, value);
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn savedata(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_savedata(&mut self, savedata: &[u8]) -> Result<(), SavedataError> {
+        mapper::load_ram(&mut self.ram, savedata)
+    }
+}
+
+impl Snapshot for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.bank1);
+        writer.write_u8(self.bank2);
+        writer.write_bool(self.banking_mode);
+        mapper::save_ram_state(&self.ram, writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = reader.read_bool()?;
+        self.bank1 = (reader.read_u8()? & 0x1f).max(1);
+        self.bank2 = reader.read_u8()? & 0x3;
+        self.banking_mode = reader.read_bool()?;
+        mapper::load_ram_state(&mut self.ram, reader)
+    }
+}
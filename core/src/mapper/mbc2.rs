@@ -0,0 +1,150 @@
+use crate::mapper::{self, Mapper, SavedataError};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// up to 256 KiB ROM and a built-in 512x4 bits RAM.
+// see https://gbdev.io/pandocs/MBC2.html
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: [u8; 512],
+    ram_enable: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom,
+            ram: [0; 512],
+            ram_enable: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3fff => mapper::read_rom_bank(&self.rom, 0, address),
+            _ => mapper::read_rom_bank(&self.rom, self.rom_bank as usize, address),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        if address > 0x3fff {
+            return;
+        }
+        // the least significant bit of the upper address byte selects the register.
+        if address & 0x100 == 0 {
+            self.ram_enable = (value & 0xf) == 0xa;
+        } else {
+            self.rom_bank = (value & 0xf).max(1);
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+        // only the lower 4 bits exist; the upper 4 bits are open bus.
+        // the 512 bytes are mirrored throughout 0xA000-0xBFFF.
+        0xf0 | self.ram[address as usize & 0x1ff]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ram_enable {
+            self.ram[address as usize & 0x1ff] = value & 0xf;
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn savedata(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_savedata(&mut self, savedata: &[u8]) -> Result<(), SavedataError> {
+        mapper::load_ram(&mut self.ram, savedata)?;
+        for value in self.ram.iter_mut() {
+            *value &= 0xf;
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot for Mbc2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank);
+        mapper::save_ram_state(&self.ram, writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank = (reader.read_u8()? & 0xf).max(1);
+        mapper::load_ram_state(&mut self.ram, reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_selected_by_address_bit_8() {
+        // (address, value, ROM bank, RAM enabled)
+        let cases = [
+            (0x0000, 0x0a, 1, true),
+            (0x0100, 0x0a, 0xa, false),
+            (0x2100, 0x03, 3, false),
+            (0x3e00, 0x0a, 1, true),
+            (0x2100, 0x00, 1, false), // bank 0 is translated to bank 1
+            (0x2100, 0x1f, 0xf, false),
+            (0x4100, 0x03, 1, false), // not a register
+        ];
+        for &(address, value, rom_bank, ram_enable) in cases.iter() {
+            let mut mbc2 = Mbc2::new(vec![0; 0x40000]);
+            mbc2.write_rom(address, value);
+            assert_eq!(
+                mbc2.rom_bank, rom_bank,
+                "{:#06x} <- {:#04x}",
+                address, value
+            );
+            assert_eq!(
+                mbc2.ram_enable, ram_enable,
+                "{:#06x} <- {:#04x}",
+                address, value
+            );
+        }
+    }
+
+    #[test]
+    fn ram_is_4_bits_and_mirrored() {
+        // (write address, value, read address, expected)
+        let cases = [
+            (0xa000, 0x5a, 0xa000, 0xfa),
+            (0xa1ff, 0x03, 0xa1ff, 0xf3),
+            (0xa010, 0x07, 0xa210, 0xf7),
+            (0xbfff, 0x0c, 0xa1ff, 0xfc),
+        ];
+        for &(write_address, value, read_address, expected) in cases.iter() {
+            let mut mbc2 = Mbc2::new(vec![0; 0x8000]);
+            mbc2.write_rom(0x0000, 0x0a);
+            mbc2.write_ram(write_address, value);
+            assert_eq!(mbc2.read_ram(read_address), expected);
+            assert_eq!(
+                mbc2.savedata()[read_address as usize & 0x1ff],
+                expected & 0xf
+            );
+        }
+    }
+
+    #[test]
+    fn load_savedata_masks_upper_bits() {
+        let mut mbc2 = Mbc2::new(vec![0; 0x8000]);
+        assert!(mbc2.load_savedata(&[0xff; 513]).is_err());
+        mbc2.load_savedata(&[0xab; 512]).unwrap();
+        assert!(mbc2.savedata().iter().all(|&value| value == 0x0b));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::mapper::rtc::Rtc;
+use crate::mapper::{self, Mapper, SavedataError};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// up to 2 MiB ROM, 32 KiB RAM and an optional Real Time Clock.
+// see https://gbdev.io/pandocs/MBC3.html
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    rtc_register: Option<u8>,
+    rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> Mbc3 {
+        Mbc3 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enable: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc_register: None,
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+        }
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3fff => mapper::read_rom_bank(&self.rom, 0, address),
+            _ => mapper::read_rom_bank(&self.rom, self.rom_bank as usize, address),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => {
+                // enables both the RAM and the RTC registers
+                self.ram_enable = (value & 0xf) == 0xa;
+            }
+            0x2000..=0x3fff => self.rom_bank = (value & 0x7f).max(1),
+            0x4000..=0x5fff => match value {
+                0x00..=0x03 => {
+                    self.ram_bank = value;
+                    self.rtc_register = None;
+                }
+                0x08..=0x0c if self.rtc.is_some() => self.rtc_register = Some(value),
+                _ => {}
+            },
+            _ => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write_latch(value);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+        match (self.rtc_register, self.rtc.as_ref()) {
+            (Some(register), Some(rtc)) => rtc.read(register),
+            _ => mapper::read_ram_bank(&self.ram, self.ram_bank as usize, address),
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enable {
+            return;
+        }
+        match (self.rtc_register, self.rtc.as_mut()) {
+            (Some(register), Some(rtc)) => rtc.write(register, value),
+            _ => mapper::write_ram_bank(&mut self.ram, self.ram_bank as usize, address, value),
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn tick(&mut self) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.tick();
+        }
+    }
+
+    fn savedata(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_savedata(&mut self, savedata: &[u8]) -> Result<(), SavedataError> {
+        mapper::load_ram(&mut self.ram, savedata)
+    }
+
+    fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+impl Snapshot for Mbc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_u8(self.rtc_register.unwrap_or(0));
+        if let Some(rtc) = self.rtc.as_ref() {
+            rtc.save_state(writer);
+        }
+        mapper::save_ram_state(&self.ram, writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank = (reader.read_u8()? & 0x7f).max(1);
+        self.ram_bank = reader.read_u8()? & 0x3;
+        self.rtc_register = match reader.read_u8()? {
+            0 => None,
+            register @ 0x08..=0x0c if self.rtc.is_some() => Some(register),
+            _ => return Err(StateError::InvalidData("RTC register")),
+        };
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.load_state(reader)?;
+        }
+        mapper::load_ram_state(&mut self.ram, reader)
+    }
+}
@@ -0,0 +1,95 @@
+use crate::mapper::{self, Mapper, SavedataError};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// up to 8 MiB ROM and 128 KiB RAM.
+// see https://gbdev.io/pandocs/MBC5.html
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    rom_bank: u16, // 9 bits
+    ram_bank: u8,
+    has_rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enable: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+        }
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3fff => mapper::read_rom_bank(&self.rom, 0, address),
+            // unlike the other MBCs, bank 0 can be mapped to 0x4000-0x7FFF.
+            _ => mapper::read_rom_bank(&self.rom, self.rom_bank as usize, address),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => {
+                // unlike MBC1, only 0x0A enables the RAM.
+                self.ram_enable = value == 0x0a;
+            }
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0xff) | ((value as u16 & 1) << 8),
+            0x4000..=0x5fff => {
+                // on rumble cartridges, bit 3 drives the motor instead of selecting a bank.
+                let mask = if self.has_rumble { 0x7 } else { 0xf };
+                self.ram_bank = value & mask;
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enable {
+            return 0xff;
+        }
+        mapper::read_ram_bank(&self.ram, self.ram_bank as usize, address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enable {
+            return;
+        }
+        mapper::write_ram_bank(&mut self.ram, self.ram_bank as usize, address, value);
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn savedata(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_savedata(&mut self, savedata: &[u8]) -> Result<(), SavedataError> {
+        mapper::load_ram(&mut self.ram, savedata)
+    }
+}
+
+impl Snapshot for Mbc5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enable);
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        mapper::save_ram_state(&self.ram, writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()? & 0x1ff;
+        self.ram_bank = reader.read_u8()? & 0xf;
+        mapper::load_ram_state(&mut self.ram, reader)
+    }
+}
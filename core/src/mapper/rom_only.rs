@@ -0,0 +1,55 @@
+use crate::mapper::{self, Mapper, SavedataError};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// 32 KiB ROM without a memory bank controller, optionally with up to 8 KiB RAM.
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom,
+            ram: vec![0; ram_size.min(0x2000)],
+        }
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        self.rom.get(address as usize).copied().unwrap_or(0xff)
+    }
+
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        mapper::read_ram_bank(&self.ram, 0, address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        mapper::write_ram_bank(&mut self.ram, 0, address, value);
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn savedata(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_savedata(&mut self, savedata: &[u8]) -> Result<(), SavedataError> {
+        mapper::load_ram(&mut self.ram, savedata)
+    }
+}
+
+impl Snapshot for RomOnly {
+    fn save_state(&self, writer: &mut StateWriter) {
+        mapper::save_ram_state(&self.ram, writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        mapper::load_ram_state(&mut self.ram, reader)
+    }
+}
@@ -0,0 +1,174 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+const CLOCKS_PER_SECOND: usize = 4194304;
+
+// size of the RTC footer appended to the cartridge RAM in save files.
+// this is the layout used by BGB, VBA-M, mGBA and SameBoy:
+//   5 x u32 current registers (S, M, H, DL, DH)
+//   5 x u32 latched registers (S, M, H, DL, DH)
+//   u64 UNIX timestamp of the moment the file was written
+pub const RTC_FOOTER_SIZE: usize = 48;
+
+// Real Time Clock of MBC3 cartridges.
+// see https://gbdev.io/pandocs/MBC3.html#the-clock-counter-registers
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rtc {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16, // 9 bits
+    pub halt: bool,
+    pub day_carry: bool,
+    pub latched: [u8; 5],
+    pub latch_armed: bool,
+    pub clock_counter: usize,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc::default()
+    }
+
+    // the RTC is driven by the emulated clock, not by the wall clock.
+    pub fn tick(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.clock_counter += 1;
+        if self.clock_counter == CLOCKS_PER_SECOND {
+            self.clock_counter = 0;
+            self.advance_second();
+        }
+    }
+
+    // each counter only carries over when it reaches its nominal limit.
+    // out-of-range values written by software wrap at the register width instead.
+    fn advance_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3f;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3f;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1f;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days == 512 {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        let mut day_high = ((self.days >> 8) & 1) as u8;
+        if self.halt {
+            day_high |= 1 << 6;
+        }
+        if self.day_carry {
+            day_high |= 1 << 7;
+        }
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            (self.days & 0xff) as u8,
+            day_high,
+        ]
+    }
+
+    // writing 0x00 and then 0x01 to 0x6000-0x7FFF latches the current time.
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.latched = self.registers();
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    // `register` is the value written to 0x4000-0x5FFF (0x08-0x0C).
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08..=0x0c => self.latched[(register - 0x08) as usize],
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3f;
+                // writing to the seconds register resets the sub-second counter.
+                self.clock_counter = 0;
+            }
+            0x09 => self.minutes = value & 0x3f,
+            0x0a => self.hours = value & 0x1f,
+            0x0b => self.days = (self.days & 0x100) | value as u16,
+            0x0c => {
+                self.days = (self.days & 0xff) | (((value & 1) as u16) << 8);
+                self.halt = value & (1 << 6) != 0;
+                self.day_carry = value & (1 << 7) != 0;
+            }
+            _ => {}
+        }
+        if (0x08..=0x0c).contains(&register) {
+            self.latched[(register - 0x08) as usize] = self.registers()[(register - 0x08) as usize];
+        }
+    }
+
+    pub fn footer(&self, timestamp: u64) -> [u8; RTC_FOOTER_SIZE] {
+        let mut footer = [0; RTC_FOOTER_SIZE];
+        for (i, value) in self
+            .registers()
+            .iter()
+            .chain(self.latched.iter())
+            .enumerate()
+        {
+            footer[i * 4..i * 4 + 4].copy_from_slice(&(*value as u32).to_le_bytes());
+        }
+        footer[40..48].copy_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+
+    // some emulators write a 32-bit timestamp (44 bytes footer), which is accepted as well.
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        let value = |i: usize| footer[i * 4];
+        self.write(0x08, value(0));
+        self.write(0x09, value(1));
+        self.write(0x0a, value(2));
+        self.write(0x0b, value(3));
+        self.write(0x0c, value(4));
+        for i in 0..5 {
+            self.latched[i] = value(5 + i);
+        }
+    }
+}
+
+impl Snapshot for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.registers());
+        writer.write_bytes(&self.latched);
+        writer.write_bool(self.latch_armed);
+        writer.write_usize(self.clock_counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let mut registers = [0; 5];
+        reader.read_bytes(&mut registers)?;
+        for (i, value) in registers.iter().enumerate() {
+            self.write(0x08 + i as u8, *value);
+        }
+        reader.read_bytes(&mut self.latched)?;
+        self.latch_armed = reader.read_bool()?;
+        self.clock_counter = reader.read_usize()?;
+        if self.clock_counter >= CLOCKS_PER_SECOND {
+            return Err(StateError::InvalidData("RTC clock counter"));
+        }
+        Ok(())
+    }
+}
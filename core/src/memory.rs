@@ -1,3 +1,6 @@
+use crate::mapper::{self, Mapper};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use std::fmt;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -6,25 +9,52 @@ extern "C" {
     fn log(s: &str);
 }
 
+const CARTRIDGE_HEADER_SIZE: usize = 0x150 - 0x134;
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+#[derive(Debug)]
+pub enum BootRomError {
+    InvalidSize(usize),
+}
+
+impl fmt::Display for BootRomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootRomError::InvalidSize(size) => write!(
+                f,
+                "boot ROM must be {} bytes (DMG) or {} bytes (CGB), got {} bytes",
+                DMG_BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BootRomError {}
+
 pub struct Memory {
-    pub cart_rom: [u8; 8 * 1024 * 1024], // support up to 8MB rom
-    pub cart_type: u8,
-    pub rom_size: u8,
-    pub ram_size: u8,
-    pub rom_bank_number: usize,
-    pub ram_bank_number: usize,
-    pub banking_mode: bool,
-    pub video_ram: [u8; 8 * 1024],
-    pub cart_ram: [u8; 32 * 1024],
-    pub work_ram: [u8; 8 * 1024],
+    pub mapper: Box<dyn Mapper>,
+    // the boot ROM is mapped over the cartridge ROM until 0xFF50 is written.
+    pub boot_rom: Option<Vec<u8>>,
+    pub cgb_mode: bool,
+    // set by the CGB boot ROM through KEY0 for DMG cartridges. the CGB registers are
+    // locked like on DMG, but the colors still come from the CGB palettes.
+    pub dmg_compat: bool,
+    pub key0: u8,
+    pub video_ram: [u8; 16 * 1024], // 2 banks of 8KB (bank 1 is CGB only)
+    pub vram_bank: usize,
+    pub work_ram: [u8; 32 * 1024], // 8 banks of 4KB (banks 2-7 are CGB only)
+    pub wram_bank: usize,
     pub obj_attr_memory: [u8; 160],
     pub joypad: u8,
     pub serial_transfer_data: u8,
     pub serial_transfer_control: u8,
-    pub divider: u8,
+    pub system_counter: u16, // DIV is the upper 8 bits
     pub timer: u8,
     pub timer_modulo: u8,
     pub timer_control: u8,
+    pub timer_overflow_clocks: u8, // clocks until TIMA is reloaded after an overflow
+    pub timer_reload_clocks: u8,   // clocks left in the M-cycle TIMA is reloaded
     pub nr10: u8,
     pub nr11: u8,
     pub nr12: u8,
@@ -44,10 +74,21 @@ pub struct Memory {
     pub nr43: u8,
     pub nr44: u8,
     pub nr50: u8,
+    pub nr51: u8,
     pub nr52: u8,
     pub wave_ram: [u8; 16],
+    // writes to NRx1, NRx2 and NRx4 as (address, previous value, value).
+    // the APU applies their effects on the length counters and the envelopes on its next tick.
+    pub sound_register_writes: Vec<(u16, u8, u8)>,
+    // the byte of wave RAM channel 3 is playing, and whether it was fetched in the current clock.
+    pub wave_ram_position: usize,
+    pub wave_ram_fetched: bool,
     pub lcd_control: u8,
     pub lcd_status: u8,
+    pub stat_write_quirk: bool,
+    pub oam_dma: u8,
+    pub oam_dma_active: bool,
+    pub oam_dma_clocks: usize,
     pub scy: u8,
     pub scx: u8,
     pub ly: u8,
@@ -56,6 +97,18 @@ pub struct Memory {
     pub obj_palette: [u8; 2],
     pub wy: u8,
     pub wx: u8,
+    pub double_speed: bool,
+    pub speed_switch_armed: bool,
+    pub hdma_source: u16,
+    pub hdma_destination: u16,
+    pub hdma_length: u8, // number of remaining 16 bytes blocks minus 1
+    pub hdma_active: bool,
+    pub cpu_stall_clocks: usize,
+    pub bg_palette_index: u8,
+    pub bg_palette_ram: [u8; 64],
+    pub obj_palette_index: u8,
+    pub obj_palette_ram: [u8; 64],
+    pub obj_priority_mode: u8,
     pub high_ram: [u8; 127],
     pub interrupt_flag: u8,
     pub interrupt_enable: u8,
@@ -65,24 +118,25 @@ pub struct Memory {
 impl Memory {
     pub fn new() -> Memory {
         Memory {
-            cart_rom: [0; 8 * 1024 * 1024],
-            cart_type: 0,
-            rom_size: 0,
-            ram_size: 0,
-            rom_bank_number: 0,
-            ram_bank_number: 0,
-            banking_mode: false,
-            video_ram: [0; 8 * 1024],
-            cart_ram: [0; 32 * 1024],
-            work_ram: [0; 8 * 1024],
+            mapper: mapper::from_rom(Vec::new()),
+            boot_rom: None,
+            cgb_mode: false,
+            dmg_compat: false,
+            key0: 0,
+            video_ram: [0; 16 * 1024],
+            vram_bank: 0,
+            work_ram: [0; 32 * 1024],
+            wram_bank: 1,
             obj_attr_memory: [0; 160],
             joypad: 0,
             serial_transfer_data: 0,
             serial_transfer_control: 0,
-            divider: 0,
+            system_counter: 0,
             timer: 0,
             timer_modulo: 0,
             timer_control: 0,
+            timer_overflow_clocks: 0,
+            timer_reload_clocks: 0,
             nr10: 0,
             nr11: 0,
             nr12: 0,
@@ -102,10 +156,18 @@ impl Memory {
             nr43: 0,
             nr44: 0,
             nr50: 0,
+            nr51: 0,
             nr52: 0,
             wave_ram: [0; 16],
+            sound_register_writes: Vec::new(),
+            wave_ram_position: 0,
+            wave_ram_fetched: false,
             lcd_control: 0,
             lcd_status: 0,
+            stat_write_quirk: false,
+            oam_dma: 0xff,
+            oam_dma_active: false,
+            oam_dma_clocks: 0,
             scy: 0,
             scx: 0,
             wy: 0,
@@ -114,6 +176,18 @@ impl Memory {
             lyc: 0,
             bg_palette: 0,
             obj_palette: [0; 2],
+            double_speed: false,
+            speed_switch_armed: false,
+            hdma_source: 0,
+            hdma_destination: 0,
+            hdma_length: 0x7f,
+            hdma_active: false,
+            cpu_stall_clocks: 0,
+            bg_palette_index: 0,
+            bg_palette_ram: [0; 64],
+            obj_palette_index: 0,
+            obj_palette_ram: [0; 64],
+            obj_priority_mode: 0,
             high_ram: [0; 127],
             interrupt_flag: 0,
             interrupt_enable: 0,
@@ -121,25 +195,143 @@ impl Memory {
         }
     }
 
+    // 0x134-0x14F: title, cartridge type, ROM/RAM size, checksums etc.
+    fn cartridge_header(&self) -> [u8; CARTRIDGE_HEADER_SIZE] {
+        let mut header = [0; CARTRIDGE_HEADER_SIZE];
+        if let Some(src) = self.mapper.rom().get(0x134..0x150) {
+            header.copy_from_slice(src);
+        }
+        header
+    }
+
+    pub fn load_boot_rom(&mut self, boot_rom: &[u8]) -> Result<(), BootRomError> {
+        if boot_rom.len() != DMG_BOOT_ROM_SIZE && boot_rom.len() != CGB_BOOT_ROM_SIZE {
+            return Err(BootRomError::InvalidSize(boot_rom.len()));
+        }
+        self.boot_rom = Some(boot_rom.to_vec());
+        Ok(())
+    }
+
+    // the CGB boot ROM writes 0x04 to KEY0 for DMG cartridges before unmapping itself,
+    // which switches to the DMG compatibility mode.
+    fn unmap_boot_rom(&mut self) {
+        let Some(boot_rom) = self.boot_rom.take() else {
+            return;
+        };
+        if boot_rom.len() == CGB_BOOT_ROM_SIZE && self.key0 & (1 << 2) != 0 {
+            self.cgb_mode = false;
+            self.dmg_compat = true;
+        }
+    }
+
+    fn read_boot_rom(&self, address: usize) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        match address {
+            // 0x100-0x1FF is left to the cartridge header.
+            0x0000..=0x00ff | 0x0200..=0x08ff => boot_rom.get(address).copied(),
+            _ => None,
+        }
+    }
+
+    // the CPU can't access VRAM while the PPU is drawing pixels (mode 3),
+    // and OAM during OAM scan and drawing pixels (modes 2 and 3).
+    // the PPU reads video_ram and obj_attr_memory directly, so it is not affected.
+    fn vram_accessible(&self) -> bool {
+        self.lcd_status & 0x3 != 3
+    }
+
+    fn oam_accessible(&self) -> bool {
+        self.lcd_status & 0x3 < 2
+    }
+
+    // while OAM DMA is running, the CPU can only access HRAM and the I/O registers.
+    fn oam_dma_conflict(&self, address: u16) -> bool {
+        self.oam_dma_active && self.oam_dma_clocks >= 4 && address < 0xff00
+    }
+
+    // while channel 3 is on, wave RAM accesses go to the byte it is playing.
+    // on DMG, they only reach it in the clock the byte is fetched. otherwise reads return 0xFF
+    // and writes are ignored.
+    fn wave_ram_index(&self, address: usize) -> Option<usize> {
+        if self.nr52 & (1 << 2) == 0 {
+            Some(address - 0xff30)
+        } else if self.cgb_mode || self.dmg_compat || self.wave_ram_fetched {
+            Some(self.wave_ram_position)
+        } else {
+            None
+        }
+    }
+
+    pub fn apu_powered(&self) -> bool {
+        self.nr52 & (1 << 7) != 0
+    }
+
+    // turning the APU off clears all the sound registers except wave RAM.
+    fn power_off_apu(&mut self) {
+        self.nr10 = 0;
+        self.nr11 = 0;
+        self.nr12 = 0;
+        self.nr13 = 0;
+        self.nr14 = 0;
+        self.nr21 = 0;
+        self.nr22 = 0;
+        self.nr23 = 0;
+        self.nr24 = 0;
+        self.nr30 = 0;
+        self.nr31 = 0;
+        self.nr32 = 0;
+        self.nr33 = 0;
+        self.nr34 = 0;
+        self.nr41 = 0;
+        self.nr42 = 0;
+        self.nr43 = 0;
+        self.nr44 = 0;
+        self.nr50 = 0;
+        self.nr51 = 0;
+        self.nr52 &= 0xf0;
+    }
+
     pub fn get_byte(&self, address: u16) -> u8 {
+        if self.oam_dma_conflict(address) {
+            // OAM reads return 0xFF, and the other reads see the byte the DMA is reading.
+            if (0xfe00..=0xfeff).contains(&address) {
+                return 0xff;
+            }
+            return self.read(self.oam_dma_source());
+        }
+        self.read(address)
+    }
+
+    // read without the OAM DMA bus conflict. used by the DMA controllers themselves.
+    fn read(&self, address: u16) -> u8 {
         let address = address as usize;
-        let rom_bank_number = self.rom_bank_number;
-        let ram_bank_number = self.ram_bank_number;
+        if let Some(value) = self.read_boot_rom(address) {
+            return value;
+        }
         match address {
-            0x0000..=0x3fff => self.cart_rom[address],
-            0x4000..=0x7fff => self.cart_rom[rom_bank_number * 0x4000 + address - 0x4000],
-            0x8000..=0x9fff => self.video_ram[address - 0x8000],
-            0xa000..=0xbfff => self.cart_ram[ram_bank_number * 0x2000 + address - 0xa000],
-            0xc000..=0xdfff => self.work_ram[address - 0xc000],
+            0x0000..=0x7fff => self.mapper.read_rom(address as u16),
+            0x8000..=0x9fff if !self.vram_accessible() => 0xff,
+            0x8000..=0x9fff => self.video_ram[self.vram_bank * 0x2000 + address - 0x8000],
+            0xa000..=0xbfff => self.mapper.read_ram(address as u16),
+            0xc000..=0xcfff => self.work_ram[address - 0xc000],
+            0xd000..=0xdfff => self.work_ram[self.wram_bank * 0x1000 + address - 0xd000],
+            0xe000..=0xfdff => self.read((address - 0x2000) as u16), // echo RAM
+            0xfe00..=0xfe9f if !self.oam_accessible() => 0xff,
             0xfe00..=0xfe9f => self.obj_attr_memory[address - 0xfe00],
+            // 0xFEA0-0xFEFF is unusable. it behaves like OAM while the PPU is using it.
+            0xfea0..=0xfeff if !self.oam_accessible() => 0xff,
+            // CGB (revision E) returns the upper nibble of the lower address byte twice.
+            0xfea0..=0xfeff if self.cgb_mode => ((address as u8) >> 4) * 0x11,
+            0xfea0..=0xfeff => 0x00,
             0xff00 => 0xc0 | self.joypad,
             0xff01 => self.serial_transfer_data,
-            0xff02 => self.serial_transfer_control,
-            0xff04 => self.divider,
+            0xff02 if self.cgb_mode => self.serial_transfer_control | 0x7c,
+            0xff02 => self.serial_transfer_control | 0x7e,
+            0xff04 => (self.system_counter >> 8) as u8,
             0xff05 => self.timer,
             0xff06 => self.timer_modulo,
-            0xff07 => self.timer_control,
-            0xff0f => self.interrupt_flag,
+            0xff07 => self.timer_control | 0xf8,
+            0xff0f => self.interrupt_flag | 0xe0,
             0xff10 => self.nr10 | 0x80,
             0xff11 => self.nr11 | 0x3f,
             0xff12 => self.nr12,
@@ -150,7 +342,7 @@ impl Memory {
             0xff18 => 0xff, // write only
             0xff19 => self.nr24 | 0xbf,
             0xff1a => self.nr30 | 0x7f,
-            0xff1b => self.nr31,
+            0xff1b => 0xff, // write only
             0xff1c => self.nr32 | 0x9f,
             0xff1d => 0xff, // write only
             0xff1e => self.nr34 | 0xbf,
@@ -159,158 +351,888 @@ impl Memory {
             0xff22 => self.nr43,
             0xff23 => self.nr44 | 0xbf,
             0xff24 => self.nr50,
+            0xff25 => self.nr51,
             0xff26 => self.nr52 | 0x70,
-            0xff30..=0xff3f => self.wave_ram[address - 0xff30],
+            0xff30..=0xff3f => match self.wave_ram_index(address) {
+                Some(i) => self.wave_ram[i],
+                None => 0xff,
+            },
             0xff40 => self.lcd_control,
-            0xff41 => self.lcd_status,
+            0xff41 => 0x80 | self.lcd_status,
             0xff42 => self.scy,
             0xff43 => self.scx,
             0xff44 => self.ly,
             0xff45 => self.lyc,
+            0xff46 => self.oam_dma,
             0xff47 => self.bg_palette,
             0xff48..=0xff49 => self.obj_palette[address - 0xff48],
             0xff4a => self.wy,
             0xff4b => self.wx,
+            // CGB registers
+            0xff4d if self.cgb_mode => {
+                0x7e | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8
+            }
+            0xff4f if self.cgb_mode => 0xfe | self.vram_bank as u8,
+            0xff55 if self.cgb_mode => ((!self.hdma_active as u8) << 7) | self.hdma_length,
+            0xff68 if self.cgb_mode => 0x40 | self.bg_palette_index,
+            0xff69 if self.cgb_mode => self.bg_palette_ram[(self.bg_palette_index & 0x3f) as usize],
+            0xff6a if self.cgb_mode => 0x40 | self.obj_palette_index,
+            0xff6b if self.cgb_mode => {
+                self.obj_palette_ram[(self.obj_palette_index & 0x3f) as usize]
+            }
+            0xff6c if self.cgb_mode => 0xfe | self.obj_priority_mode,
+            0xff70 if self.cgb_mode => 0xf8 | self.wram_bank as u8,
             0xff80..=0xfffe => self.high_ram[address - 0xff80],
             0xffff => self.interrupt_enable,
-            _ => 0xff, // other areas are not supported yet
+            _ => 0xff, // unmapped
         }
     }
 
-    pub fn get_word(&self, address: u16) -> u16 {
-        let lower = self.get_byte(address) as u16;
-        let upper = self.get_byte(address + 1) as u16;
-        (upper << 8) | lower
-    }
-
     pub fn set_byte(&mut self, address: u16, value: u8) {
-        let address = address as usize;
-        let ram_bank_number = self.ram_bank_number;
-        if address <= 0x7fff {
-            if 0x1 <= self.cart_type && self.cart_type <= 0x3 {
-                // MBC1
-                match address {
-                    0x2000..=0x3fff => {
-                        let mask = match self.rom_size {
-                            0x00 => 0x1,
-                            0x01 => 0x3,
-                            0x02 => 0x7,
-                            0x03 => 0xf,
-                            _ => 0x1f,
-                        };
-                        let prev = self.rom_bank_number;
-                        self.rom_bank_number = if (value & 0x1f) == 0 {
-                            1
-                        } else {
-                            (value & mask) as usize
-                        };
-                        self.rom_bank_number |= prev & 0x60;
-                    }
-                    0x4000..=0x5fff => {
-                        if self.banking_mode {
-                            self.ram_bank_number = (value & 0x3) as usize;
-                        } else {
-                            self.rom_bank_number |= ((value & 0x3) << 5) as usize;
-                        }
-                    }
-                    0x6000..=0x7fff => {
-                        if value == 0 {
-                            // ROM bank mode (max 8KB RAM, 2MB ROM)
-                            self.banking_mode = false;
-                            self.ram_bank_number = 0;
-                        } else {
-                            // RAM bank mode (max 32KB RAM, 512KB ROM)
-                            self.banking_mode = true;
-                            self.rom_bank_number &= 0x1f;
-                        }
-                    }
-                    _ => {}
-                }
-            } else if 0x19 <= self.cart_type && self.cart_type <= 0x1b {
-                // MBC5
-                match address {
-                    0x2000..=0x2fff => {
-                        let prev = self.rom_bank_number;
-                        self.rom_bank_number = value as usize;
-                        self.rom_bank_number |= prev & 0x100;
-                    }
-                    0x3000..=0x3fff => {
-                        self.rom_bank_number |= ((value & 1) as usize) << 9;
-                    }
-                    0x4000..=0x5fff => {
-                        self.ram_bank_number = (value as usize) & 0xf;
-                    }
-                    _ => {}
-                }
-            }
+        if self.oam_dma_conflict(address) {
             return;
         }
+        let address = address as usize;
         match address {
-            0x8000..=0x9fff => self.video_ram[address - 0x8000] = value,
-            0xa000..=0xbfff => self.cart_ram[ram_bank_number * 0x2000 + address - 0xa000] = value,
-            0xc000..=0xdfff => self.work_ram[address - 0xc000] = value,
+            0x0000..=0x7fff => self.mapper.write_rom(address as u16, value),
+            0x8000..=0x9fff if !self.vram_accessible() => {}
+            0x8000..=0x9fff => self.video_ram[self.vram_bank * 0x2000 + address - 0x8000] = value,
+            0xa000..=0xbfff => self.mapper.write_ram(address as u16, value),
+            0xc000..=0xcfff => self.work_ram[address - 0xc000] = value,
+            0xd000..=0xdfff => self.work_ram[self.wram_bank * 0x1000 + address - 0xd000] = value,
+            0xe000..=0xfdff => self.set_byte((address - 0x2000) as u16, value), // echo RAM
+            0xfe00..=0xfe9f if !self.oam_accessible() => {}
             0xfe00..=0xfe9f => self.obj_attr_memory[address - 0xfe00] = value,
             0xff00 => self.joypad = 0xc0 | (value & 0x30) | (self.joypad & 0xf),
             0xff01 => self.serial_transfer_data = value,
             0xff02 => self.serial_transfer_control = value,
-            0xff04 => self.divider = 0x00, // Writing any value to this register resets it to 0x00.
-            0xff05 => self.timer = value,
-            0xff06 => self.timer_modulo = value,
+            // writing any value resets the whole system counter, not only DIV.
+            0xff04 => self.system_counter = 0,
+            // TIMA writes are ignored in the M-cycle it is reloaded from TMA,
+            // and cancel the pending reload and interrupt in the M-cycle before.
+            0xff05 if self.timer_reload_clocks > 0 => {}
+            0xff05 => {
+                self.timer = value;
+                self.timer_overflow_clocks = 0;
+            }
+            // TMA writes in the M-cycle TIMA is reloaded also go to TIMA.
+            0xff06 => {
+                self.timer_modulo = value;
+                if self.timer_reload_clocks > 0 {
+                    self.timer = value;
+                }
+            }
             0xff07 => self.timer_control = value,
             0xff0f => self.interrupt_flag = value,
+            // while the APU is off, only the length counters can be written (DMG only).
+            0xff11 if !self.apu_powered() && !self.cgb_mode => {
+                self.sound_register_writes.push((0xff11, self.nr11, value));
+                self.nr11 = value & 0x3f;
+            }
+            0xff16 if !self.apu_powered() && !self.cgb_mode => {
+                self.sound_register_writes.push((0xff16, self.nr21, value));
+                self.nr21 = value & 0x3f;
+            }
+            0xff1b if !self.apu_powered() && !self.cgb_mode => {
+                self.sound_register_writes.push((0xff1b, self.nr31, value));
+                self.nr31 = value;
+            }
+            0xff20 if !self.apu_powered() && !self.cgb_mode => {
+                self.sound_register_writes.push((0xff20, self.nr41, value));
+                self.nr41 = value | 0xc0;
+            }
+            0xff10..=0xff25 if !self.apu_powered() => {}
             0xff10 => self.nr10 = value | 0x80,
-            0xff11 => self.nr11 = value,
-            0xff12 => self.nr12 = value,
+            0xff11 => {
+                self.sound_register_writes.push((0xff11, self.nr11, value));
+                self.nr11 = value;
+            }
+            0xff12 => {
+                self.sound_register_writes.push((0xff12, self.nr12, value));
+                self.nr12 = value;
+                if !dac_enabled(value) {
+                    self.nr52 &= !1;
+                }
+            }
             0xff13 => self.nr13 = value,
-            0xff14 => self.nr14 = value | 0x38,
-            0xff16 => self.nr21 = value,
-            0xff17 => self.nr22 = value,
+            0xff14 => {
+                self.sound_register_writes.push((0xff14, self.nr14, value));
+                self.nr14 = value | 0x38;
+            }
+            0xff16 => {
+                self.sound_register_writes.push((0xff16, self.nr21, value));
+                self.nr21 = value;
+            }
+            0xff17 => {
+                self.sound_register_writes.push((0xff17, self.nr22, value));
+                self.nr22 = value;
+                if !dac_enabled(value) {
+                    self.nr52 &= !(1 << 1);
+                }
+            }
             0xff18 => self.nr23 = value,
-            0xff19 => self.nr24 = value | 0x38,
-            0xff1a => self.nr30 = value | 0x7f,
-            0xff1b => self.nr31 = value,
+            0xff19 => {
+                self.sound_register_writes.push((0xff19, self.nr24, value));
+                self.nr24 = value | 0x38;
+            }
+            0xff1a => {
+                self.nr30 = value | 0x7f;
+                if value & (1 << 7) == 0 {
+                    self.nr52 &= !(1 << 2);
+                }
+            }
+            0xff1b => {
+                self.sound_register_writes.push((0xff1b, self.nr31, value));
+                self.nr31 = value;
+            }
             0xff1c => self.nr32 = value | 0x9f,
             0xff1d => self.nr33 = value,
-            0xff1e => self.nr34 = value | 0x38,
-            0xff20 => self.nr41 = value | 0xc0,
-            0xff21 => self.nr42 = value,
+            0xff1e => {
+                self.sound_register_writes.push((0xff1e, self.nr34, value));
+                self.nr34 = value | 0x38;
+            }
+            0xff20 => {
+                self.sound_register_writes.push((0xff20, self.nr41, value));
+                self.nr41 = value | 0xc0;
+            }
+            0xff21 => {
+                self.sound_register_writes.push((0xff21, self.nr42, value));
+                self.nr42 = value;
+                if !dac_enabled(value) {
+                    self.nr52 &= !(1 << 3);
+                }
+            }
             0xff22 => self.nr43 = value,
-            0xff23 => self.nr44 = value | 0x3f,
+            0xff23 => {
+                self.sound_register_writes.push((0xff23, self.nr44, value));
+                self.nr44 = value | 0x3f;
+            }
             0xff24 => self.nr50 = value,
+            0xff25 => self.nr51 = value,
             0xff26 => {
+                if value & (1 << 7) == 0 && self.apu_powered() {
+                    self.power_off_apu();
+                }
                 let prev = self.nr52;
                 self.nr52 = (value & (1 << 7)) | 0x70 | (prev & 0xf);
             }
-            0xff30..=0xff3f => self.wave_ram[address - 0xff30] = value,
+            0xff30..=0xff3f => {
+                if let Some(i) = self.wave_ram_index(address) {
+                    self.wave_ram[i] = value;
+                }
+            }
             0xff40 => self.lcd_control = value,
-            0xff41 => self.lcd_status = value,
+            0xff41 => {
+                // the mode and the LY=LYC flag are read-only.
+                self.lcd_status = (value & 0x78) | (self.lcd_status & 0x07);
+                // on DMG, writing to STAT behaves as if 0xFF was written for one cycle.
+                self.stat_write_quirk = !self.cgb_mode;
+            }
             0xff42 => self.scy = value,
             0xff43 => self.scx = value,
             // since ly is read only, it is omitted
             0xff45 => self.lyc = value,
             0xff46 => {
                 // writing to this register starts a DMA transfer from ROM or RAM to OAM.
-                for i in 0..=0x9f {
-                    let src = ((value as u16) << 8) | i;
-                    let dst = 0xfe00 | i;
-                    let byte = self.get_byte(src);
-                    self.set_byte(dst, byte);
-                }
+                // the transfer itself is driven by tick_oam_dma.
+                self.oam_dma = value;
+                self.oam_dma_active = true;
+                self.oam_dma_clocks = 0;
             }
             0xff47 => self.bg_palette = value,
             0xff48..=0xff49 => self.obj_palette[address - 0xff48] = value,
             0xff4a => self.wy = value,
             0xff4b => self.wx = value,
+            0xff50 if value != 0 => self.unmap_boot_rom(),
+            // CGB registers
+            0xff4c if self.cgb_mode && self.boot_rom.is_some() => self.key0 = value,
+            0xff4d if self.cgb_mode => self.speed_switch_armed = value & 1 != 0,
+            0xff4f if self.cgb_mode => self.vram_bank = (value & 1) as usize,
+            0xff51 if self.cgb_mode => {
+                self.hdma_source = (self.hdma_source & 0x00ff) | ((value as u16) << 8)
+            }
+            0xff52 if self.cgb_mode => {
+                self.hdma_source = (self.hdma_source & 0xff00) | (value & 0xf0) as u16
+            }
+            0xff53 if self.cgb_mode => {
+                self.hdma_destination =
+                    (self.hdma_destination & 0x00ff) | (((value & 0x1f) as u16) << 8)
+            }
+            0xff54 if self.cgb_mode => {
+                self.hdma_destination = (self.hdma_destination & 0xff00) | (value & 0xf0) as u16
+            }
+            0xff55 if self.cgb_mode => self.start_hdma(value),
+            0xff68 if self.cgb_mode => self.bg_palette_index = value & 0xbf,
+            0xff69 if self.cgb_mode => {
+                self.bg_palette_ram[(self.bg_palette_index & 0x3f) as usize] = value;
+                self.bg_palette_index = next_palette_index(self.bg_palette_index);
+            }
+            0xff6a if self.cgb_mode => self.obj_palette_index = value & 0xbf,
+            0xff6b if self.cgb_mode => {
+                self.obj_palette_ram[(self.obj_palette_index & 0x3f) as usize] = value;
+                self.obj_palette_index = next_palette_index(self.obj_palette_index);
+            }
+            0xff6c if self.cgb_mode => self.obj_priority_mode = value & 1,
+            // writing 0 selects bank 1.
+            0xff70 if self.cgb_mode => self.wram_bank = ((value & 0x7) as usize).max(1),
             0xff80..=0xfffe => self.high_ram[address - 0xff80] = value,
             0xffff => self.interrupt_enable = value,
-            _ => (), // other areas are not supported yet
+            _ => (), // read only or unmapped
         }
     }
 
-    pub fn set_word(&mut self, address: u16, value: u16) {
-        let lower = (value & 0xff) as u8;
-        let upper = (value >> 8) as u8;
-        self.set_byte(address, lower);
-        self.set_byte(address + 1, upper);
+    // a write to HDMA5 (0xFF55) starts a VRAM DMA transfer.
+    // see https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+    fn start_hdma(&mut self, value: u8) {
+        if self.hdma_active && value & (1 << 7) == 0 {
+            // writing 0 to bit 7 terminates an active HBlank DMA.
+            self.hdma_active = false;
+            return;
+        }
+        self.hdma_length = value & 0x7f;
+        if value & (1 << 7) != 0 {
+            // HBlank DMA: 16 bytes are transferred at the start of each HBlank.
+            self.hdma_active = true;
+        } else {
+            // General Purpose DMA: all data is transferred at once.
+            self.hdma_active = true;
+            while self.hdma_active {
+                self.transfer_hdma_block();
+            }
+        }
+    }
+
+    fn transfer_hdma_block(&mut self) {
+        for _ in 0..16 {
+            let byte = self.read(self.hdma_source);
+            let dst = self.vram_bank * 0x2000 + (self.hdma_destination & 0x1fff) as usize;
+            self.video_ram[dst] = byte;
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_destination = (self.hdma_destination + 1) & 0x1fff;
+        }
+        // the CPU is halted during the transfer.
+        // a block takes 8 M-cycles in single speed mode and 16 M-cycles in double speed mode.
+        self.cpu_stall_clocks += if self.double_speed { 64 } else { 32 };
+        if self.hdma_length == 0 {
+            self.hdma_active = false;
+            self.hdma_length = 0x7f;
+        } else {
+            self.hdma_length -= 1;
+        }
+    }
+
+    // OAM DMA copies one byte per M-cycle after a delay of one M-cycle, so it takes 640 clocks.
+    pub fn tick_oam_dma(&mut self) {
+        if !self.oam_dma_active {
+            return;
+        }
+        self.oam_dma_clocks += 1;
+        if self.oam_dma_clocks & 0x3 != 0 || self.oam_dma_clocks < 8 {
+            return;
+        }
+        let i = self.oam_dma_clocks / 4 - 2;
+        self.obj_attr_memory[i] = self.read(self.oam_dma_source());
+        if i == 0x9f {
+            self.oam_dma_active = false;
+        }
+    }
+
+    // the address of the byte copied by the current M-cycle of OAM DMA.
+    // during the start delay, it is the first byte.
+    fn oam_dma_source(&self) -> u16 {
+        let i = (self.oam_dma_clocks / 4).saturating_sub(2).min(0x9f);
+        let src = ((self.oam_dma as u16) << 8) | i as u16;
+        if src >= 0xe000 {
+            // 0xE000-0xFFFF reads from work RAM, like echo RAM.
+            src - 0x2000
+        } else {
+            src
+        }
+    }
+
+    // called by the PPU when it enters HBlank.
+    pub fn hblank_dma(&mut self) {
+        if self.hdma_active {
+            self.transfer_hdma_block();
+        }
+    }
+}
+
+// the DAC of channels 1, 2 and 4 is on when any of the upper 5 bits of NRx2 is set.
+pub fn dac_enabled(nrx2: u8) -> bool {
+    nrx2 & 0xf8 != 0
+}
+
+// BCPS/OCPS bit 7 enables auto-increment of the palette index after each write to BCPD/OCPD.
+fn next_palette_index(index: u8) -> u8 {
+    if index & (1 << 7) != 0 {
+        (1 << 7) | ((index + 1) & 0x3f)
+    } else {
+        index
+    }
+}
+
+// The cartridge ROM is not part of a save state; it is expected to be loaded
+// with `Emulator::load_rom` before the state is restored.
+// The cartridge header is stored to detect states made with another cartridge.
+impl Snapshot for Memory {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.cartridge_header());
+        self.mapper.save_state(writer);
+        let boot_rom = self.boot_rom.as_deref().unwrap_or(&[]);
+        writer.write_usize(boot_rom.len());
+        writer.write_bytes(boot_rom);
+        writer.write_bool(self.cgb_mode);
+        writer.write_bool(self.dmg_compat);
+        writer.write_u8(self.key0);
+        writer.write_bytes(&self.video_ram);
+        writer.write_usize(self.vram_bank);
+        writer.write_bytes(&self.work_ram);
+        writer.write_usize(self.wram_bank);
+        writer.write_bytes(&self.obj_attr_memory);
+        writer.write_u8(self.joypad);
+        writer.write_u8(self.serial_transfer_data);
+        writer.write_u8(self.serial_transfer_control);
+        writer.write_u16(self.system_counter);
+        writer.write_u8(self.timer);
+        writer.write_u8(self.timer_modulo);
+        writer.write_u8(self.timer_control);
+        writer.write_u8(self.timer_overflow_clocks);
+        writer.write_u8(self.timer_reload_clocks);
+        writer.write_u8(self.nr10);
+        writer.write_u8(self.nr11);
+        writer.write_u8(self.nr12);
+        writer.write_u8(self.nr13);
+        writer.write_u8(self.nr14);
+        writer.write_u8(self.nr21);
+        writer.write_u8(self.nr22);
+        writer.write_u8(self.nr23);
+        writer.write_u8(self.nr24);
+        writer.write_u8(self.nr30);
+        writer.write_u8(self.nr31);
+        writer.write_u8(self.nr32);
+        writer.write_u8(self.nr33);
+        writer.write_u8(self.nr34);
+        writer.write_u8(self.nr41);
+        writer.write_u8(self.nr42);
+        writer.write_u8(self.nr43);
+        writer.write_u8(self.nr44);
+        writer.write_u8(self.nr50);
+        writer.write_u8(self.nr51);
+        writer.write_u8(self.nr52);
+        writer.write_bytes(&self.wave_ram);
+        writer.write_usize(self.sound_register_writes.len());
+        for &(address, previous, value) in self.sound_register_writes.iter() {
+            writer.write_u16(address);
+            writer.write_u8(previous);
+            writer.write_u8(value);
+        }
+        writer.write_usize(self.wave_ram_position);
+        writer.write_bool(self.wave_ram_fetched);
+        writer.write_u8(self.lcd_control);
+        writer.write_u8(self.lcd_status);
+        writer.write_bool(self.stat_write_quirk);
+        writer.write_u8(self.oam_dma);
+        writer.write_bool(self.oam_dma_active);
+        writer.write_usize(self.oam_dma_clocks);
+        writer.write_u8(self.scy);
+        writer.write_u8(self.scx);
+        writer.write_u8(self.ly);
+        writer.write_u8(self.lyc);
+        writer.write_u8(self.bg_palette);
+        writer.write_bytes(&self.obj_palette);
+        writer.write_u8(self.wy);
+        writer.write_u8(self.wx);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+        writer.write_u16(self.hdma_source);
+        writer.write_u16(self.hdma_destination);
+        writer.write_u8(self.hdma_length);
+        writer.write_bool(self.hdma_active);
+        writer.write_usize(self.cpu_stall_clocks);
+        writer.write_u8(self.bg_palette_index);
+        writer.write_bytes(&self.bg_palette_ram);
+        writer.write_u8(self.obj_palette_index);
+        writer.write_bytes(&self.obj_palette_ram);
+        writer.write_u8(self.obj_priority_mode);
+        writer.write_bytes(&self.high_ram);
+        writer.write_u8(self.interrupt_flag);
+        writer.write_u8(self.interrupt_enable);
+        writer.write_bool(self.interrupt_master_enable);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let mut header = [0; CARTRIDGE_HEADER_SIZE];
+        reader.read_bytes(&mut header)?;
+        if header != self.cartridge_header() {
+            return Err(StateError::CartridgeMismatch);
+        }
+        self.mapper.load_state(reader)?;
+        self.boot_rom = match reader.read_usize()? {
+            0 => None,
+            n @ (DMG_BOOT_ROM_SIZE | CGB_BOOT_ROM_SIZE) => {
+                let mut boot_rom = vec![0; n];
+                reader.read_bytes(&mut boot_rom)?;
+                Some(boot_rom)
+            }
+            _ => return Err(StateError::InvalidData("boot ROM size")),
+        };
+        self.cgb_mode = reader.read_bool()?;
+        self.dmg_compat = reader.read_bool()?;
+        self.key0 = reader.read_u8()?;
+        reader.read_bytes(&mut self.video_ram)?;
+        self.vram_bank = reader.read_usize()? & 1;
+        reader.read_bytes(&mut self.work_ram)?;
+        self.wram_bank = (reader.read_usize()? & 0x7).max(1);
+        reader.read_bytes(&mut self.obj_attr_memory)?;
+        self.joypad = reader.read_u8()?;
+        self.serial_transfer_data = reader.read_u8()?;
+        self.serial_transfer_control = reader.read_u8()?;
+        self.system_counter = reader.read_u16()?;
+        self.timer = reader.read_u8()?;
+        self.timer_modulo = reader.read_u8()?;
+        self.timer_control = reader.read_u8()?;
+        self.timer_overflow_clocks = reader.read_u8()?;
+        self.timer_reload_clocks = reader.read_u8()?;
+        self.nr10 = reader.read_u8()?;
+        self.nr11 = reader.read_u8()?;
+        self.nr12 = reader.read_u8()?;
+        self.nr13 = reader.read_u8()?;
+        self.nr14 = reader.read_u8()?;
+        self.nr21 = reader.read_u8()?;
+        self.nr22 = reader.read_u8()?;
+        self.nr23 = reader.read_u8()?;
+        self.nr24 = reader.read_u8()?;
+        self.nr30 = reader.read_u8()?;
+        self.nr31 = reader.read_u8()?;
+        self.nr32 = reader.read_u8()?;
+        self.nr33 = reader.read_u8()?;
+        self.nr34 = reader.read_u8()?;
+        self.nr41 = reader.read_u8()?;
+        self.nr42 = reader.read_u8()?;
+        self.nr43 = reader.read_u8()?;
+        self.nr44 = reader.read_u8()?;
+        self.nr50 = reader.read_u8()?;
+        self.nr51 = reader.read_u8()?;
+        self.nr52 = reader.read_u8()?;
+        reader.read_bytes(&mut self.wave_ram)?;
+        // the APU applies the writes on its next tick, so only a few can be pending.
+        let writes = reader.read_usize()?;
+        if writes > 16 {
+            return Err(StateError::InvalidData("sound register writes"));
+        }
+        self.sound_register_writes.clear();
+        for _ in 0..writes {
+            let address = reader.read_u16()?;
+            let previous = reader.read_u8()?;
+            let value = reader.read_u8()?;
+            self.sound_register_writes.push((address, previous, value));
+        }
+        self.wave_ram_position = reader.read_usize()?;
+        if self.wave_ram_position >= self.wave_ram.len() {
+            return Err(StateError::InvalidData("wave RAM position"));
+        }
+        self.wave_ram_fetched = reader.read_bool()?;
+        self.lcd_control = reader.read_u8()?;
+        self.lcd_status = reader.read_u8()?;
+        self.stat_write_quirk = reader.read_bool()?;
+        self.oam_dma = reader.read_u8()?;
+        self.oam_dma_active = reader.read_bool()?;
+        self.oam_dma_clocks = reader.read_usize()?;
+        if self.oam_dma_clocks >= 4 * (0xa0 + 2) {
+            return Err(StateError::InvalidData("OAM DMA progress"));
+        }
+        self.scy = reader.read_u8()?;
+        self.scx = reader.read_u8()?;
+        self.ly = reader.read_u8()?;
+        self.lyc = reader.read_u8()?;
+        self.bg_palette = reader.read_u8()?;
+        reader.read_bytes(&mut self.obj_palette)?;
+        self.wy = reader.read_u8()?;
+        self.wx = reader.read_u8()?;
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_armed = reader.read_bool()?;
+        self.hdma_source = reader.read_u16()?;
+        self.hdma_destination = reader.read_u16()? & 0x1fff;
+        self.hdma_length = reader.read_u8()? & 0x7f;
+        self.hdma_active = reader.read_bool()?;
+        self.cpu_stall_clocks = reader.read_usize()?;
+        self.bg_palette_index = reader.read_u8()? & 0xbf;
+        reader.read_bytes(&mut self.bg_palette_ram)?;
+        self.obj_palette_index = reader.read_u8()? & 0xbf;
+        reader.read_bytes(&mut self.obj_palette_ram)?;
+        self.obj_priority_mode = reader.read_u8()? & 1;
+        reader.read_bytes(&mut self.high_ram)?;
+        self.interrupt_flag = reader.read_u8()?;
+        self.interrupt_enable = reader.read_u8()?;
+        self.interrupt_master_enable = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_ram_mirrors_work_ram() {
+        // (address written, address read back)
+        let cases = [
+            (0xc000, 0xe000),
+            (0xcfff, 0xefff),
+            (0xd000, 0xf000),
+            (0xddff, 0xfdff),
+            (0xe123, 0xc123),
+            (0xfdff, 0xddff),
+        ];
+        for (i, &(write_addr, read_addr)) in cases.iter().enumerate() {
+            let mut memory = Memory::new();
+            let value = 0x40 + i as u8;
+            memory.set_byte(write_addr, value);
+            assert_eq!(
+                memory.get_byte(read_addr),
+                value,
+                "write {:#06x}, read {:#06x}",
+                write_addr,
+                read_addr
+            );
+        }
+    }
+
+    #[test]
+    fn echo_ram_follows_wram_bank() {
+        let mut memory = Memory::new();
+        memory.cgb_mode = true;
+        memory.set_byte(0xff70, 3);
+        memory.set_byte(0xd010, 0x5a);
+        assert_eq!(memory.get_byte(0xf010), 0x5a);
+        memory.set_byte(0xff70, 2);
+        assert_ne!(memory.get_byte(0xf010), 0x5a);
+    }
+
+    #[test]
+    fn unusable_area() {
+        // (address, cgb_mode, PPU mode, expected)
+        let cases = [
+            (0xfea0, false, 0, 0x00),
+            (0xfeff, false, 1, 0x00),
+            (0xfea0, false, 2, 0xff),
+            (0xfef0, false, 3, 0xff),
+            (0xfea0, true, 0, 0xaa),
+            (0xfeb7, true, 1, 0xbb),
+            (0xfeff, true, 0, 0xff),
+            (0xfec0, true, 2, 0xff),
+        ];
+        for &(address, cgb_mode, mode, expected) in cases.iter() {
+            let mut memory = Memory::new();
+            memory.cgb_mode = cgb_mode;
+            memory.lcd_status = mode;
+            memory.set_byte(address, 0x12); // writes are ignored
+            assert_eq!(
+                memory.get_byte(address),
+                expected,
+                "address {:#06x}, cgb {}, mode {}",
+                address,
+                cgb_mode,
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn nr51_is_stored() {
+        for value in [0x00, 0x0f, 0xf0, 0x5a, 0xff] {
+            let mut memory = Memory::new();
+            memory.set_byte(0xff26, 0x80); // APU power on
+            memory.set_byte(0xff25, value);
+            assert_eq!(memory.get_byte(0xff25), value);
+            assert_eq!(memory.nr51, value);
+        }
+    }
+
+    #[test]
+    fn io_register_read_masks() {
+        // (address, value written, expected read back) on DMG.
+        let cases = [
+            (0xff01, 0x00, 0x00),
+            (0xff02, 0x00, 0x7e),
+            (0xff03, 0x00, 0xff),
+            (0xff07, 0x00, 0xf8),
+            (0xff08, 0x00, 0xff),
+            (0xff0e, 0x00, 0xff),
+            (0xff0f, 0x00, 0xe0),
+            (0xff10, 0x00, 0x80),
+            (0xff11, 0x00, 0x3f),
+            (0xff12, 0x00, 0x00),
+            (0xff13, 0x00, 0xff),
+            (0xff14, 0x00, 0xbf),
+            (0xff15, 0x00, 0xff),
+            (0xff16, 0x00, 0x3f),
+            (0xff17, 0x00, 0x00),
+            (0xff18, 0x00, 0xff),
+            (0xff19, 0x00, 0xbf),
+            (0xff1a, 0x00, 0x7f),
+            (0xff1b, 0x00, 0xff),
+            (0xff1c, 0x00, 0x9f),
+            (0xff1d, 0x00, 0xff),
+            (0xff1e, 0x00, 0xbf),
+            (0xff1f, 0x00, 0xff),
+            (0xff20, 0x00, 0xff),
+            (0xff21, 0x00, 0x00),
+            (0xff22, 0x00, 0x00),
+            (0xff23, 0x00, 0xbf),
+            (0xff24, 0x00, 0x00),
+            (0xff25, 0x00, 0x00),
+            (0xff26, 0x80, 0xf0),
+            (0xff27, 0x00, 0xff),
+            (0xff2f, 0x00, 0xff),
+            (0xff41, 0x00, 0x80),
+            (0xff4c, 0x00, 0xff),
+            (0xff4d, 0x00, 0xff), // CGB registers are unmapped on DMG
+            (0xff4f, 0x00, 0xff),
+            (0xff55, 0x00, 0xff),
+            (0xff68, 0x00, 0xff),
+            (0xff70, 0x00, 0xff),
+            (0xff7f, 0x00, 0xff),
+            (0xffff, 0x00, 0x00),
+        ];
+        for &(address, value, expected) in cases.iter() {
+            let mut memory = Memory::new();
+            memory.set_byte(0xff26, 0x80); // APU power on
+            memory.set_byte(address, value);
+            assert_eq!(
+                memory.get_byte(address),
+                expected,
+                "address {:#06x}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn apu_power_off() {
+        // (address, value written while off, expected read back) on DMG.
+        // only the length counters are writable while the APU is off.
+        let cases = [
+            (0xff10, 0x7f, 0x80),
+            (0xff11, 0xff, 0x3f),
+            (0xff12, 0xf3, 0x00),
+            (0xff16, 0xff, 0x3f),
+            (0xff17, 0xf3, 0x00),
+            (0xff1a, 0x80, 0x7f),
+            (0xff1c, 0x60, 0x9f),
+            (0xff21, 0xf3, 0x00),
+            (0xff24, 0x77, 0x00),
+            (0xff25, 0xff, 0x00),
+        ];
+        for &(address, value, expected) in cases.iter() {
+            let mut memory = Memory::new();
+            memory.set_byte(0xff26, 0x80);
+            memory.set_byte(0xff24, 0x77);
+            memory.set_byte(0xff25, 0xff);
+            memory.set_byte(0xff26, 0x00); // clears all the registers
+            memory.set_byte(address, value);
+            assert_eq!(
+                memory.get_byte(address),
+                expected,
+                "address {:#06x}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn dac_disable_turns_channel_off() {
+        // (NRx2 address, channel bit in NR52)
+        let cases = [(0xff12, 0), (0xff17, 1), (0xff1a, 2), (0xff21, 3)];
+        for &(address, bit) in cases.iter() {
+            let mut memory = Memory::new();
+            memory.set_byte(0xff26, 0x80);
+            memory.nr52 |= 0xf;
+            memory.set_byte(address, 0x00);
+            assert_eq!(memory.get_byte(0xff26) & 0xf, 0xf ^ (1 << bit));
+        }
+    }
+
+    #[test]
+    fn timer_writes_around_reload() {
+        // (overflow clocks, reload clocks, address, value, expected TIMA, reload pending)
+        let cases = [
+            (4, 0, 0xff05, 0x12, 0x12, false), // cancels the reload
+            (0, 4, 0xff05, 0x12, 0x80, false), // ignored while reloading
+            (0, 4, 0xff06, 0x34, 0x34, false), // TMA goes to TIMA too
+            (4, 0, 0xff06, 0x34, 0x00, true),
+        ];
+        for &(overflow, reload, address, value, timer, pending) in cases.iter() {
+            let mut memory = Memory::new();
+            memory.timer = if reload > 0 { 0x80 } else { 0x00 };
+            memory.timer_overflow_clocks = overflow;
+            memory.timer_reload_clocks = reload;
+            memory.set_byte(address, value);
+            assert_eq!(memory.timer, timer, "address {:#06x}", address);
+            assert_eq!(memory.timer_overflow_clocks > 0, pending);
+        }
+    }
+
+    #[test]
+    fn boot_rom_overlay() {
+        // (boot ROM size, address, expected) with 0xB0 in the boot ROM and 0xCA in the cartridge.
+        let cases = [
+            (DMG_BOOT_ROM_SIZE, 0x0000, 0xb0),
+            (DMG_BOOT_ROM_SIZE, 0x00ff, 0xb0),
+            (DMG_BOOT_ROM_SIZE, 0x0100, 0xca),
+            (DMG_BOOT_ROM_SIZE, 0x0200, 0xca),
+            (CGB_BOOT_ROM_SIZE, 0x0000, 0xb0),
+            (CGB_BOOT_ROM_SIZE, 0x00ff, 0xb0),
+            (CGB_BOOT_ROM_SIZE, 0x0100, 0xca), // the cartridge header
+            (CGB_BOOT_ROM_SIZE, 0x01ff, 0xca),
+            (CGB_BOOT_ROM_SIZE, 0x0200, 0xb0),
+            (CGB_BOOT_ROM_SIZE, 0x08ff, 0xb0),
+            (CGB_BOOT_ROM_SIZE, 0x0900, 0xca),
+        ];
+        for &(size, address, expected) in cases.iter() {
+            let mut memory = Memory::new();
+            memory.mapper = mapper::from_rom(vec![0xca; 0x8000]);
+            memory.load_boot_rom(&vec![0xb0; size]).unwrap();
+            assert_eq!(memory.get_byte(address), expected, "{:#06x}", address);
+            memory.set_byte(0xff50, 0x00); // only non-zero values unmap it
+            assert_eq!(memory.get_byte(address), expected, "{:#06x}", address);
+            memory.set_byte(0xff50, 0x01);
+            assert!(memory.boot_rom.is_none());
+            assert_eq!(memory.get_byte(address), 0xca, "{:#06x}", address);
+        }
+    }
+
+    #[test]
+    fn key0_selects_dmg_compat_mode() {
+        // (boot ROM size, KEY0, CGB mode after unmapping, DMG compatibility mode)
+        let cases = [
+            (CGB_BOOT_ROM_SIZE, 0x04, false, true),
+            (CGB_BOOT_ROM_SIZE, 0x80, true, false),
+            (CGB_BOOT_ROM_SIZE, 0x00, true, false),
+            (DMG_BOOT_ROM_SIZE, 0x04, true, false), // only the CGB boot ROM has KEY0
+        ];
+        for &(size, key0, cgb_mode, dmg_compat) in cases.iter() {
+            let mut memory = Memory::new();
+            memory.cgb_mode = true;
+            memory.load_boot_rom(&vec![0; size]).unwrap();
+            memory.set_byte(0xff4c, key0);
+            memory.set_byte(0xff50, 0x01);
+            assert_eq!(memory.cgb_mode, cgb_mode, "KEY0 {:#04x}", key0);
+            assert_eq!(memory.dmg_compat, dmg_compat, "KEY0 {:#04x}", key0);
+        }
+
+        // KEY0 is locked once the boot ROM is unmapped.
+        let mut memory = Memory::new();
+        memory.cgb_mode = true;
+        memory.set_byte(0xff4c, 0x04);
+        assert_eq!(memory.key0, 0);
+    }
+
+    #[test]
+    fn div_write_resets_system_counter() {
+        let mut memory = Memory::new();
+        memory.system_counter = 0xabcc;
+        assert_eq!(memory.get_byte(0xff04), 0xab);
+        memory.set_byte(0xff04, 0x55);
+        assert_eq!(memory.system_counter, 0);
+    }
+
+    #[test]
+    fn oam_dma_timing() {
+        // (M-cycles after the write to 0xFF46, bytes copied to OAM, still running)
+        let cases = [
+            (0, 0, true),
+            (1, 0, true), // start delay
+            (2, 1, true),
+            (3, 2, true),
+            (81, 80, true),
+            (160, 159, true),
+            (161, 160, false),
+        ];
+        for &(m_cycles, copied, active) in cases.iter() {
+            let mut memory = Memory::new();
+            for i in 0..0xa0 {
+                memory.set_byte(0xc100 + i, 0x80 | i as u8);
+            }
+            memory.set_byte(0xff46, 0xc1);
+            for _ in 0..m_cycles * 4 {
+                memory.tick_oam_dma();
+            }
+            assert_eq!(memory.oam_dma_active, active, "{} M-cycles", m_cycles);
+            for i in 0..0xa0 {
+                let expected = if i < copied { 0x80 | i as u8 } else { 0 };
+                assert_eq!(
+                    memory.obj_attr_memory[i], expected,
+                    "{} M-cycles, byte {}",
+                    m_cycles, i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn oam_dma_bus_conflicts() {
+        // (M-cycles after the write to 0xFF46, address read by the CPU, value read)
+        let cases = [
+            (0, 0xc000, 0x11),  // the transfer has not started yet
+            (1, 0xc000, 0x80),  // the first byte is on the bus during the start delay
+            (1, 0x0150, 0x80),  // ROM
+            (10, 0xc000, 0x88), // WRAM
+            (10, 0xa000, 0x88), // cartridge RAM
+            (10, 0xfe00, 0xff), // OAM
+            (10, 0xff80, 0x22), // HRAM is still accessible
+            (10, 0xffff, 0x00), // IE
+            (161, 0xc000, 0x11),
+        ];
+        for &(m_cycles, address, value) in cases.iter() {
+            let mut memory = Memory::new();
+            for i in 0..0xa0 {
+                memory.set_byte(0xc100 + i, 0x80 | i as u8);
+            }
+            memory.set_byte(0xc000, 0x11);
+            memory.set_byte(0xff80, 0x22);
+            memory.set_byte(0xff46, 0xc1);
+            for _ in 0..m_cycles * 4 {
+                memory.tick_oam_dma();
+            }
+            assert_eq!(
+                memory.get_byte(address),
+                value,
+                "{} M-cycles, {:#06x}",
+                m_cycles,
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn oam_dma_restart() {
+        let mut memory = Memory::new();
+        for i in 0..0xa0 {
+            memory.set_byte(0xc100 + i, 0x80 | i as u8);
+            memory.set_byte(0xc200 + i, 0x40 | i as u8);
+        }
+        memory.set_byte(0xff46, 0xc1);
+        for _ in 0..50 * 4 {
+            memory.tick_oam_dma();
+        }
+        // writing 0xFF46 again restarts the transfer from the first byte of the new source.
+        memory.set_byte(0xff46, 0xc2);
+        for _ in 0..160 * 4 {
+            memory.tick_oam_dma();
+        }
+        assert!(memory.oam_dma_active);
+        assert_eq!(memory.obj_attr_memory[0], 0x40);
+        assert_eq!(memory.obj_attr_memory[0x9e], 0x40 | 0x9e);
+        // the last byte has not been copied by either transfer yet.
+        assert_eq!(memory.obj_attr_memory[0x9f], 0);
+        for _ in 0..4 {
+            memory.tick_oam_dma();
+        }
+        assert!(!memory.oam_dma_active);
+        for i in 0..0xa0 {
+            assert_eq!(memory.obj_attr_memory[i], 0x40 | i as u8, "byte {}", i);
+        }
     }
 }
@@ -1,5 +1,7 @@
 use crate::memory::Memory;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
@@ -33,6 +35,67 @@ impl From<u8> for Color {
     }
 }
 
+// a background or window pixel before the palette is applied.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BgPixel {
+    pub color_id: u8,
+    pub palette: u8,    // CGB only
+    pub priority: bool, // CGB only
+}
+
+// an object pixel before the palette is applied.
+// color_id 0 is transparent.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ObjPixel {
+    pub color_id: u8,
+    pub palette: u8,
+    pub bg_priority: bool,
+    pub oam_index: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FetcherStep {
+    GetTile,
+    GetTileDataLow,
+    GetTileDataHigh,
+    Push,
+}
+
+// the background/window fetcher of mode 3.
+// each step except Push takes 2 dots; Push is retried every dot until the BG FIFO is empty.
+// see https://gbdev.io/pandocs/pixel_fifo.html
+#[derive(Clone, Copy, Debug)]
+pub struct Fetcher {
+    pub step: FetcherStep,
+    pub dots: usize, // dots spent on the current step
+    pub tile_x: usize,
+    pub window: bool,
+    pub tile_idx: u8,
+    pub attr: u8, // CGB only
+    pub data_low: u8,
+    pub data_high: u8,
+}
+
+impl Fetcher {
+    fn new(window: bool) -> Fetcher {
+        Fetcher {
+            step: FetcherStep::GetTile,
+            dots: 0,
+            tile_x: 0,
+            window,
+            tile_idx: 0,
+            attr: 0,
+            data_low: 0,
+            data_high: 0,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.step = FetcherStep::GetTile;
+        self.dots = 0;
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct LCDControl {
     pub lcd_enable: bool,
@@ -83,25 +146,25 @@ impl From<u8> for LCDStatus {
     }
 }
 
-impl Into<u8> for LCDStatus {
-    fn into(self) -> u8 {
+impl From<LCDStatus> for u8 {
+    fn from(stat: LCDStatus) -> u8 {
         let mut value = 0;
-        if self.ly_interrupt_enable {
+        if stat.ly_interrupt_enable {
             value |= 1 << 6;
         }
-        if self.oam_interrupt_enable {
+        if stat.oam_interrupt_enable {
             value |= 1 << 5;
         }
-        if self.vblank_interrupt_enable {
+        if stat.vblank_interrupt_enable {
             value |= 1 << 4;
         }
-        if self.hblank_interrupt_enable {
+        if stat.hblank_interrupt_enable {
             value |= 1 << 3;
         }
-        if self.ly_compare {
+        if stat.ly_compare {
             value |= 1 << 2;
         }
-        value |= self.mode;
+        value |= stat.mode;
         value
     }
 }
@@ -110,9 +173,22 @@ pub struct PPU {
     pub memory: Rc<RefCell<Memory>>,
     pub clocks_to_finish: usize,
     pub frame_buffer: [u8; DISPLAY_SIZE * 4],
-    pub obj_idx: Vec<(usize, usize)>,
+    pub obj_idx: Vec<(usize, usize, usize)>, // (x, OAM index, row within the object)
     pub window_line_counter: usize,
     pub wy_cond_triggered: bool,
+    // mode 3 state
+    pub fetcher: Fetcher,
+    pub bg_fifo: VecDeque<BgPixel>,
+    pub obj_fifo: VecDeque<ObjPixel>,
+    pub lx: usize,              // x coordinate of the next pixel to be shifted out
+    pub discard_pixels: usize,  // pixels thrown away at the start of the line (SCX % 8)
+    pub first_fetch_done: bool, // the first tile fetch of each line is thrown away
+    pub obj_fetch_dots: usize,  // remaining dots of the current object fetch
+    pub mode_3_clocks: usize,   // dots spent in mode 3 so far
+    pub window_rendered: bool,  // whether the window has been drawn on this line
+    pub lcd_enabled: bool,
+    pub blank_frame: bool, // the first frame after the LCD is turned on is not displayed
+    pub stat_line: bool,
 }
 
 impl PPU {
@@ -124,37 +200,63 @@ impl PPU {
             obj_idx: Vec::with_capacity(10),
             window_line_counter: 0,
             wy_cond_triggered: false,
+            fetcher: Fetcher::new(false),
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            lx: 0,
+            discard_pixels: 0,
+            first_fetch_done: false,
+            obj_fetch_dots: 0,
+            mode_3_clocks: 0,
+            window_rendered: false,
+            lcd_enabled: true,
+            blank_frame: false,
+            stat_line: false,
         }
     }
 
     fn set_ly(&mut self, value: u8) {
-        let mut stat = LCDStatus::from(self.memory.borrow().lcd_status);
+        // the LY=LYC flag is updated by update_stat_line.
         self.memory.borrow_mut().ly = value;
-        stat.ly_compare = value == self.memory.borrow().lyc;
-        if stat.ly_interrupt_enable && stat.ly_compare {
-            self.memory.borrow_mut().interrupt_flag |= 1 << 1;
+    }
+
+    // all STAT interrupt sources are ORed into a single line and the interrupt is requested
+    // on its rising edge only. a source becoming active while another one is still active
+    // doesn't request a new interrupt ("STAT blocking").
+    fn update_stat_line(&mut self) {
+        let mut memory = self.memory.borrow_mut();
+        let mut stat = LCDStatus::from(memory.lcd_status);
+        stat.ly_compare = memory.ly == memory.lyc;
+        memory.lcd_status = stat.into();
+        let line = (stat.ly_interrupt_enable && stat.ly_compare)
+            || (stat.hblank_interrupt_enable && stat.mode == 0)
+            || (stat.vblank_interrupt_enable && stat.mode == 1)
+            || (stat.oam_interrupt_enable && stat.mode == 2);
+        // on DMG, a write to STAT enables all sources for one cycle, so it requests
+        // an interrupt during HBlank, VBlank or when LY=LYC.
+        let quirk =
+            memory.stat_write_quirk && (stat.mode == 0 || stat.mode == 1 || stat.ly_compare);
+        memory.stat_write_quirk = false;
+        if (line || quirk) && !self.stat_line {
+            memory.interrupt_flag |= 1 << 1;
         }
-        self.memory.borrow_mut().lcd_status = stat.into();
+        self.stat_line = line;
     }
 
     fn enter_mode_0(&mut self) {
         let mut stat = LCDStatus::from(self.memory.borrow().lcd_status);
         stat.mode = 0;
         self.memory.borrow_mut().lcd_status = stat.into();
-        if stat.hblank_interrupt_enable {
-            self.memory.borrow_mut().interrupt_flag |= 1 << 1;
-        }
-        self.clocks_to_finish = 204;
+        // a scanline is always 456 dots, so HBlank is shortened by a longer mode 3.
+        self.clocks_to_finish = 456 - 80 - self.mode_3_clocks;
     }
 
     fn enter_mode_1(&mut self) {
+        self.blank_frame = false;
         let mut stat = LCDStatus::from(self.memory.borrow().lcd_status);
         stat.mode = 1;
         self.memory.borrow_mut().lcd_status = stat.into();
         self.memory.borrow_mut().interrupt_flag |= 1;
-        if stat.vblank_interrupt_enable {
-            self.memory.borrow_mut().interrupt_flag |= 1 << 1;
-        }
         self.clocks_to_finish = 456;
     }
 
@@ -162,9 +264,6 @@ impl PPU {
         let mut stat = LCDStatus::from(self.memory.borrow().lcd_status);
         stat.mode = 2;
         self.memory.borrow_mut().lcd_status = stat.into();
-        if stat.oam_interrupt_enable {
-            self.memory.borrow_mut().interrupt_flag |= 1 << 1;
-        }
         self.clocks_to_finish = 80;
     }
 
@@ -172,10 +271,55 @@ impl PPU {
         let mut stat = LCDStatus::from(self.memory.borrow().lcd_status);
         stat.mode = 3;
         self.memory.borrow_mut().lcd_status = stat.into();
-        self.clocks_to_finish = 172;
+        self.fetcher = Fetcher::new(false);
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.lx = 0;
+        self.discard_pixels = (self.memory.borrow().scx & 0x7) as usize;
+        self.first_fetch_done = false;
+        self.obj_fetch_dots = 0;
+        self.mode_3_clocks = 0;
+        self.window_rendered = false;
+    }
+
+    // LCDC.7 stops the PPU. LY is held at 0 and STAT reports mode 0 until it is turned on again.
+    fn turn_lcd_off(&mut self) {
+        self.lcd_enabled = false;
+        let mut stat = LCDStatus::from(self.memory.borrow().lcd_status);
+        stat.mode = 0;
+        self.memory.borrow_mut().lcd_status = stat.into();
+        self.memory.borrow_mut().ly = 0;
+        self.stat_line = false;
+        self.clear_frame_buffer();
+    }
+
+    fn turn_lcd_on(&mut self) {
+        self.lcd_enabled = true;
+        self.blank_frame = true;
+        self.window_line_counter = 0;
+        self.wy_cond_triggered = false;
+        self.enter_mode_2();
+        self.set_ly(0);
     }
 
     pub fn tick(&mut self) {
+        let lcd_enable = LCDControl::from(self.memory.borrow().lcd_control).lcd_enable;
+        if lcd_enable != self.lcd_enabled {
+            if lcd_enable {
+                self.turn_lcd_on();
+            } else {
+                self.turn_lcd_off();
+            }
+        }
+        if !self.lcd_enabled {
+            self.memory.borrow_mut().stat_write_quirk = false;
+            return;
+        }
+        self.step();
+        self.update_stat_line();
+    }
+
+    fn step(&mut self) {
         let ly = self.memory.borrow().ly;
         let wy = self.memory.borrow().wy;
         let stat = LCDStatus::from(self.memory.borrow().lcd_status);
@@ -183,16 +327,25 @@ impl PPU {
             // WY condition is checked at the start of Mode 2 only.
             self.wy_cond_triggered |= ly == wy;
         }
+        if stat.mode == 3 {
+            // DRAWING PIXELS
+            // mode 3 has no fixed length. it ends when 160 pixels have been shifted out.
+            self.tick_pixel_fifo(ly as usize);
+            if self.lx == DISPLAY_WIDTH {
+                if self.window_rendered {
+                    self.window_line_counter += 1;
+                }
+                self.enter_mode_0();
+                self.memory.borrow_mut().hblank_dma();
+            }
+            return;
+        }
         self.clocks_to_finish -= 1;
         if self.clocks_to_finish == 0 {
             if stat.mode == 2 {
                 // OAM SCAN
                 self.oam_scan(ly as usize);
                 self.enter_mode_3();
-            } else if stat.mode == 3 {
-                // DRAWING PIXELS
-                self.render(ly as usize);
-                self.enter_mode_0();
             } else if stat.mode == 0 {
                 // HORIZONTAL BLANK
                 if ly < 143 {
@@ -217,6 +370,290 @@ impl PPU {
         }
     }
 
+    // advance mode 3 by one dot.
+    fn tick_pixel_fifo(&mut self, ly: usize) {
+        self.mode_3_clocks += 1;
+        if self.obj_fetch_dots > 0 {
+            // the background fetcher and the pixel output are paused during an object fetch.
+            self.obj_fetch_dots -= 1;
+            return;
+        }
+        let control = LCDControl::from(self.memory.borrow().lcd_control);
+        let cgb_mode = self.memory.borrow().cgb_mode;
+
+        if control.obj_enable && self.first_fetch_done && !self.bg_fifo.is_empty() {
+            let next_obj = self
+                .obj_idx
+                .iter()
+                .position(|&(obj_x, _, _)| obj_x != 0 && obj_x <= self.lx + 8);
+            if let Some(i) = next_obj {
+                let (obj_x, idx, row) = self.obj_idx.remove(i);
+                self.fetch_object(idx, obj_x, row);
+                // 6 dots for the fetch itself, plus waiting for the background fetcher
+                // to finish the tile it is working on.
+                let bg_fetch_dots = match self.fetcher.step {
+                    FetcherStep::GetTile => self.fetcher.dots,
+                    FetcherStep::GetTileDataLow => 2 + self.fetcher.dots,
+                    FetcherStep::GetTileDataHigh => 4 + self.fetcher.dots,
+                    FetcherStep::Push => 5,
+                };
+                self.obj_fetch_dots = 6 + 5 - bg_fetch_dots.min(5) - 1;
+                return;
+            }
+        }
+
+        let window_enable = (cgb_mode || control.bg_win_enable) && control.win_enable;
+        let wx = self.memory.borrow().wx as usize;
+        if window_enable
+            && !self.fetcher.window
+            && self.wy_cond_triggered
+            && wx < 167
+            && self.lx + 7 >= wx
+            && self.discard_pixels == 0
+        {
+            // switching to the window restarts the fetcher.
+            self.bg_fifo.clear();
+            self.fetcher = Fetcher::new(true);
+            self.window_rendered = true;
+            if wx < 7 {
+                self.discard_pixels = 7 - wx;
+            }
+        }
+
+        self.tick_fetcher(ly, control);
+
+        let Some(bg_pixel) = self.bg_fifo.pop_front() else {
+            return;
+        };
+        if self.discard_pixels > 0 {
+            self.discard_pixels -= 1;
+            return;
+        }
+        let obj_pixel = self.obj_fifo.pop_front();
+        let obj_pixel = obj_pixel.filter(|obj| obj.color_id != 0 && control.obj_enable);
+        self.draw_pixel(ly, self.lx, bg_pixel, obj_pixel);
+        self.lx += 1;
+    }
+
+    fn tick_fetcher(&mut self, ly: usize, control: LCDControl) {
+        self.fetcher.dots += 1;
+        match self.fetcher.step {
+            FetcherStep::GetTile => {
+                if self.fetcher.dots < 2 {
+                    return;
+                }
+                let (tile_map_offset, cgb_mode) = {
+                    let memory = self.memory.borrow();
+                    let (tile_map_area, y, x) = if self.fetcher.window {
+                        (
+                            control.win_tile_map_area,
+                            self.window_line_counter,
+                            self.fetcher.tile_x,
+                        )
+                    } else {
+                        (
+                            control.bg_tile_map_area,
+                            (ly + memory.scy as usize) % 256,
+                            (memory.scx as usize / 8 + self.fetcher.tile_x) % 32,
+                        )
+                    };
+                    let tile_map_base = if tile_map_area { 0x1c00 } else { 0x1800 };
+                    (tile_map_base + (y / 8) * 32 + x, memory.cgb_mode)
+                };
+                let memory = self.memory.borrow();
+                self.fetcher.tile_idx = memory.video_ram[tile_map_offset];
+                // tile attributes are stored in VRAM bank 1 on CGB.
+                self.fetcher.attr = if cgb_mode {
+                    memory.video_ram[0x2000 + tile_map_offset]
+                } else {
+                    0
+                };
+                drop(memory);
+                self.fetcher.step = FetcherStep::GetTileDataLow;
+                self.fetcher.dots = 0;
+            }
+            FetcherStep::GetTileDataLow | FetcherStep::GetTileDataHigh => {
+                if self.fetcher.dots < 2 {
+                    return;
+                }
+                let offset = self.tile_data_offset(ly, control);
+                let memory = self.memory.borrow();
+                if self.fetcher.step == FetcherStep::GetTileDataLow {
+                    self.fetcher.data_low = memory.video_ram[offset];
+                    self.fetcher.step = FetcherStep::GetTileDataHigh;
+                } else {
+                    self.fetcher.data_high = memory.video_ram[offset + 1];
+                    self.fetcher.step = FetcherStep::Push;
+                }
+                drop(memory);
+                self.fetcher.dots = 0;
+                if self.fetcher.step == FetcherStep::Push && self.first_fetch_done {
+                    self.push_bg_tile();
+                }
+            }
+            FetcherStep::Push => {
+                if !self.first_fetch_done {
+                    // the first fetch of the line is discarded.
+                    self.first_fetch_done = true;
+                    self.fetcher.restart();
+                    return;
+                }
+                self.push_bg_tile();
+            }
+        }
+    }
+
+    // push the fetched tile row into the BG FIFO. it only succeeds when the FIFO is empty.
+    fn push_bg_tile(&mut self) {
+        if !self.bg_fifo.is_empty() {
+            return;
+        }
+        let attr = self.fetcher.attr;
+        for i in 0..8 {
+            let bit = if attr & (1 << 5) != 0 { i } else { 7 - i };
+            let color_id =
+                ((self.fetcher.data_low >> bit) & 1) | (((self.fetcher.data_high >> bit) & 1) << 1);
+            self.bg_fifo.push_back(BgPixel {
+                color_id,
+                palette: attr & 0x7,
+                priority: attr & (1 << 7) != 0,
+            });
+        }
+        self.fetcher.tile_x += 1;
+        self.fetcher.restart();
+    }
+
+    // offset in video_ram of the tile row currently being fetched.
+    fn tile_data_offset(&self, ly: usize, control: LCDControl) -> usize {
+        let tile_idx = self.fetcher.tile_idx;
+        let tile_data_addr = if control.bg_win_tile_data_area {
+            0x8000u16.wrapping_add((tile_idx as u16) << 4)
+        } else {
+            0x9000u16.wrapping_add_signed(((tile_idx as i8) as i16) << 4)
+        };
+        let mut y = if self.fetcher.window {
+            self.window_line_counter % 8
+        } else {
+            (ly + self.memory.borrow().scy as usize) % 8
+        };
+        if self.fetcher.attr & (1 << 6) != 0 {
+            y = 7 - y;
+        }
+        let bank = ((self.fetcher.attr >> 3) & 1) as usize;
+        bank * 0x2000 + (tile_data_addr - 0x8000) as usize + y * 2
+    }
+
+    // fetch a row of an object and merge it into the object FIFO.
+    // `row` is the line of the object selected by the OAM scan. OAM and LCDC can
+    // change during mode 3, so the row is wrapped to the current object height.
+    fn fetch_object(&mut self, obj_idx: usize, obj_x: usize, row: usize) {
+        let control = LCDControl::from(self.memory.borrow().lcd_control);
+        let memory = self.memory.borrow();
+        let addr = obj_idx * 4;
+        let obj_h = if control.obj_size { 16 } else { 8 };
+        let mut tile_idx = memory.obj_attr_memory[addr + 2] as usize;
+        let attr = memory.obj_attr_memory[addr + 3];
+        if control.obj_size {
+            tile_idx &= 0xfe;
+        }
+        let mut y = row & (obj_h - 1);
+        if attr & (1 << 6) != 0 {
+            y = obj_h - y - 1;
+        }
+        if y >= 8 {
+            tile_idx += 1;
+            y -= 8;
+        }
+        let (bank, palette) = if memory.cgb_mode {
+            (((attr >> 3) & 1) as usize, attr & 0x7)
+        } else {
+            (0, (attr >> 4) & 1)
+        };
+        let offset = bank * 0x2000 + (tile_idx << 4) + y * 2;
+        let data_low = memory.video_ram[offset];
+        let data_high = memory.video_ram[offset + 1];
+        // on CGB, overlapping objects are prioritized by their OAM index.
+        let oam_priority = memory.cgb_mode && memory.obj_priority_mode & 1 == 0;
+        drop(memory);
+
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(ObjPixel::default());
+        }
+        // objects partially off the left edge of the screen lose their leftmost pixels.
+        let clipped = (self.lx + 8).saturating_sub(obj_x);
+        for i in clipped..8 {
+            let bit = if attr & (1 << 5) != 0 { i } else { 7 - i };
+            let color_id = ((data_low >> bit) & 1) | (((data_high >> bit) & 1) << 1);
+            let pixel = ObjPixel {
+                color_id,
+                palette,
+                bg_priority: attr & (1 << 7) != 0,
+                oam_index: obj_idx as u8,
+            };
+            let slot = &mut self.obj_fifo[i - clipped];
+            if slot.color_id == 0
+                || (oam_priority && color_id != 0 && pixel.oam_index < slot.oam_index)
+            {
+                *slot = pixel;
+            }
+        }
+    }
+
+    fn draw_pixel(&mut self, y: usize, x: usize, bg_pixel: BgPixel, obj_pixel: Option<ObjPixel>) {
+        if self.blank_frame {
+            return;
+        }
+        let control = LCDControl::from(self.memory.borrow().lcd_control);
+        let cgb_mode = self.memory.borrow().cgb_mode;
+        // objects are always on top when LCDC.0 is cleared.
+        let obj_pixel = obj_pixel.filter(|obj| {
+            !control.bg_win_enable
+                || bg_pixel.color_id == 0
+                || (!obj.bg_priority && !bg_pixel.priority)
+        });
+        let memory = self.memory.borrow();
+        if cgb_mode {
+            let (palette_ram, palette, color_id) = match obj_pixel {
+                Some(obj) => (&memory.obj_palette_ram, obj.palette, obj.color_id),
+                None => (&memory.bg_palette_ram, bg_pixel.palette, bg_pixel.color_id),
+            };
+            let offset = (palette as usize) * 8 + (color_id as usize) * 2;
+            let color = u16::from_le_bytes([palette_ram[offset], palette_ram[offset + 1]]);
+            drop(memory);
+            self.set_pixel_rgb555(y, x, color);
+        } else if memory.dmg_compat {
+            // BGP, OBP0 and OBP1 pick one of the 4 colors of the palettes set up by the boot ROM.
+            let (palette_ram, palette, color_id) = match obj_pixel {
+                Some(obj) => {
+                    let shade =
+                        (memory.obj_palette[obj.palette as usize] >> (obj.color_id * 2)) & 0x3;
+                    (&memory.obj_palette_ram, obj.palette, shade)
+                }
+                None if !control.bg_win_enable => (&memory.bg_palette_ram, 0, 0),
+                None => {
+                    let shade = (memory.bg_palette >> (bg_pixel.color_id * 2)) & 0x3;
+                    (&memory.bg_palette_ram, 0, shade)
+                }
+            };
+            let offset = (palette as usize) * 8 + (color_id as usize) * 2;
+            let color = u16::from_le_bytes([palette_ram[offset], palette_ram[offset + 1]]);
+            drop(memory);
+            self.set_pixel_rgb555(y, x, color);
+        } else {
+            let color: Color = match obj_pixel {
+                Some(obj) => {
+                    let palette = memory.obj_palette[obj.palette as usize];
+                    ((palette >> (obj.color_id * 2)) & 0x3).into()
+                }
+                // on DMG, LCDC.0 blanks the background and the window.
+                None if !control.bg_win_enable => Color::White,
+                None => ((memory.bg_palette >> (bg_pixel.color_id * 2)) & 0x3).into(),
+            };
+            drop(memory);
+            self.set_pixel(y, x, color);
+        }
+    }
+
     pub fn set_pixel(&mut self, screen_y: usize, screen_x: usize, color: Color) {
         const WHITE: u8 = 255;
         const LIGHT_GRAY: u8 = 170;
@@ -252,130 +689,38 @@ impl PPU {
         }
     }
 
-    pub fn get_background_pixel(&self, screen_y: usize, screen_x: usize) -> Color {
-        let lcdc: LCDControl = self.memory.borrow().lcd_control.into();
-        if !lcdc.bg_win_enable {
-            return Color::White;
-        }
-        let y = (screen_y + self.memory.borrow().scy as usize) % 256;
-        let x = (screen_x + self.memory.borrow().scx as usize) % 256;
-        let tile_map_base_addr = if lcdc.bg_tile_map_area {
-            0x9c00u16
-        } else {
-            0x9800u16
-        };
-        let tile_map_addr = tile_map_base_addr + ((y / 8) * 32 + (x / 8)) as u16;
-        let tile_idx = self.memory.borrow().get_byte(tile_map_addr);
-        let tile_data_addr = if lcdc.bg_win_tile_data_area {
-            0x8000u16.wrapping_add((tile_idx as u16) << 4)
-        } else {
-            0x9000u16.wrapping_add_signed(((tile_idx as i8) as i16) << 4)
-        };
-        let y = y % 8;
-        let x = x % 8;
-        let tile_data = self
-            .memory
-            .borrow()
-            .get_word(tile_data_addr + (y as u16) * 2);
-        let color_id = ((tile_data >> (7 - x)) & 1) + (((tile_data >> (15 - x)) & 1) << 1);
-        let palette = self.memory.borrow().bg_palette;
-        let color: Color = ((palette >> (color_id * 2)) & 0x3).into();
-        color
-    }
-
-    pub fn get_window_pixel(&self, screen_x: usize) -> Option<Color> {
-        let lcdc: LCDControl = self.memory.borrow().lcd_control.into();
-        if !lcdc.bg_win_enable || !lcdc.win_enable {
-            return None;
-        }
-        let wx = self.memory.borrow().wx as usize;
-        if wx > screen_x + 7 {
-            return None;
-        }
-        let x = screen_x + 7 - wx;
-        let tile_map_base_addr = if lcdc.win_tile_map_area {
-            0x9c00u16
-        } else {
-            0x9800u16
-        };
-        let tile_map_addr =
-            tile_map_base_addr + ((self.window_line_counter / 8) * 32 + (x / 8)) as u16;
-        let tile_idx = self.memory.borrow().get_byte(tile_map_addr);
-        let tile_data_addr = if lcdc.bg_win_tile_data_area {
-            0x8000u16.wrapping_add((tile_idx as u16) << 4)
-        } else {
-            0x9000u16.wrapping_add_signed(((tile_idx as i8) as i16) << 4)
-        };
-        let y = self.window_line_counter % 8;
-        let x = x % 8;
-        let tile_data = self
-            .memory
-            .borrow()
-            .get_word(tile_data_addr + (y as u16) * 2);
-        let color_id = ((tile_data >> (7 - x)) & 1) + (((tile_data >> (15 - x)) & 1) << 1);
-        let palette = self.memory.borrow().bg_palette;
-        let color: Color = ((palette >> (color_id * 2)) & 0x3).into();
-        Some(color)
-    }
-
-    pub fn get_object_pixel(
-        &self,
-        obj_idx: usize,
-        screen_y: usize,
-        screen_x: usize,
-    ) -> Option<Color> {
-        let control = LCDControl::from(self.memory.borrow().lcd_control);
-        let addr = 0xfe00 + obj_idx * 4;
-        let obj_h = if control.obj_size { 16 } else { 8 };
-        let obj_y = self.memory.borrow().get_byte(addr as u16) as usize;
-        let obj_x = self.memory.borrow().get_byte((addr + 1) as u16) as usize;
-        let mut tile_idx = self.memory.borrow().get_byte((addr + 2) as u16) as usize;
-        if control.obj_size {
-            tile_idx &= 0xfe;
-        }
-        let attr = self.memory.borrow().get_byte((addr + 3) as u16) as usize;
-        let mut y = screen_y + 16 - obj_y;
-        let mut x = screen_x + 8 - obj_x;
-        if attr & (1 << 6) != 0 {
-            y = obj_h - y - 1;
-        }
-        if attr & (1 << 5) != 0 {
-            x = 7 - x;
-        }
-        if y >= 8 {
-            tile_idx += 1;
-            y -= 8;
-        }
-        let tile_data_addr = 0x8000u16.wrapping_add((tile_idx as u16) << 4);
-        let tile_data = self
-            .memory
-            .borrow()
-            .get_word(tile_data_addr + (y as u16) * 2);
-        let color_id = ((tile_data >> (7 - x)) & 1) + (((tile_data >> (15 - x)) & 1) << 1);
-        if color_id == 0 {
-            return None;
-        }
-        let palette = self.memory.borrow().obj_palette[(attr >> 4) & 1];
-        let color: Color = ((palette >> (color_id * 2)) & 0x3).into();
-        Some(color)
+    // writes a CGB color (RGB555) to the frame buffer.
+    pub fn set_pixel_rgb555(&mut self, screen_y: usize, screen_x: usize, color: u16) {
+        // scale 5 bit components up to 8 bits.
+        let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+        let index = (screen_y * DISPLAY_WIDTH + screen_x) * 4;
+        self.frame_buffer[index] = scale(color & 0x1f);
+        self.frame_buffer[index + 1] = scale((color >> 5) & 0x1f);
+        self.frame_buffer[index + 2] = scale((color >> 10) & 0x1f);
+        self.frame_buffer[index + 3] = 255;
     }
 
     pub fn oam_scan(&mut self, y: usize) {
         let control = LCDControl::from(self.memory.borrow().lcd_control);
         self.obj_idx.clear();
         for idx in 0..40 {
-            let addr = 0xfe00 + idx * 4;
+            let addr = idx * 4;
             let obj_h = if control.obj_size { 16 } else { 8 };
-            let obj_y = self.memory.borrow().get_byte(addr) as usize;
-            let obj_x = self.memory.borrow().get_byte(addr + 1) as usize;
+            let obj_y = self.memory.borrow().obj_attr_memory[addr] as usize;
+            let obj_x = self.memory.borrow().obj_attr_memory[addr + 1] as usize;
             if obj_y + obj_h > y + 16 && obj_y <= y + 16 {
-                self.obj_idx.push((obj_x, idx as usize));
+                self.obj_idx.push((obj_x, idx, y + 16 - obj_y));
                 if self.obj_idx.len() >= 10 {
                     break;
                 }
             }
         }
-        self.obj_idx.sort_by(|(x1, _), (x2, _)| x1.cmp(x2));
+        // on CGB, objects are prioritized by their OAM index unless OPRI selects
+        // the DMG-style priority by X coordinate.
+        let memory = self.memory.borrow();
+        if !memory.cgb_mode || memory.obj_priority_mode & 1 != 0 {
+            self.obj_idx.sort_by_key(|(x, _, _)| *x);
+        }
     }
 
     pub fn clear_frame_buffer(&mut self) {
@@ -385,43 +730,180 @@ impl PPU {
             }
         }
     }
+}
 
-    pub fn render(&mut self, y: usize) {
-        let control = LCDControl::from(self.memory.borrow().lcd_control);
-        let wx = self.memory.borrow().wx;
-        let wx_cond_triggered = wx < 168;
-        let is_window_visible = control.bg_win_enable
-            && control.win_enable
-            && self.wy_cond_triggered
-            && wx_cond_triggered;
-        for x in 0..DISPLAY_WIDTH {
-            let mut bg_pixel = self.get_background_pixel(y, x);
-            self.set_pixel(y, x, bg_pixel);
-            if is_window_visible {
-                if let Some(win_pixel) = self.get_window_pixel(x) {
-                    bg_pixel = win_pixel;
-                    self.set_pixel(y, x, win_pixel);
-                }
+impl Snapshot for PPU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.clocks_to_finish);
+        writer.write_u8(self.obj_idx.len() as u8);
+        for &(obj_x, idx, row) in self.obj_idx.iter() {
+            writer.write_u8(obj_x as u8);
+            writer.write_u8(idx as u8);
+            writer.write_u8(row as u8);
+        }
+        writer.write_usize(self.window_line_counter);
+        writer.write_bool(self.wy_cond_triggered);
+        self.fetcher.save_state(writer);
+        writer.write_u8(self.bg_fifo.len() as u8);
+        for pixel in self.bg_fifo.iter() {
+            writer.write_u8(pixel.color_id);
+            writer.write_u8(pixel.palette);
+            writer.write_bool(pixel.priority);
+        }
+        writer.write_u8(self.obj_fifo.len() as u8);
+        for pixel in self.obj_fifo.iter() {
+            writer.write_u8(pixel.color_id);
+            writer.write_u8(pixel.palette);
+            writer.write_bool(pixel.bg_priority);
+            writer.write_u8(pixel.oam_index);
+        }
+        writer.write_usize(self.lx);
+        writer.write_usize(self.discard_pixels);
+        writer.write_bool(self.first_fetch_done);
+        writer.write_usize(self.obj_fetch_dots);
+        writer.write_usize(self.mode_3_clocks);
+        writer.write_bool(self.window_rendered);
+        writer.write_bool(self.lcd_enabled);
+        writer.write_bool(self.blank_frame);
+        writer.write_bool(self.stat_line);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.clocks_to_finish = reader.read_usize()?;
+        let n = reader.read_u8()? as usize;
+        if n > 10 {
+            return Err(StateError::InvalidData("too many objects on a scanline"));
+        }
+        self.obj_idx.clear();
+        for _ in 0..n {
+            let obj_x = reader.read_u8()? as usize;
+            let idx = reader.read_u8()? as usize;
+            if idx >= 40 {
+                return Err(StateError::InvalidData("object index"));
             }
-            if control.obj_enable {
-                let obj_idx = self.obj_idx.clone();
-                for (obj_x, idx) in obj_idx {
-                    if obj_x > x + 8 || obj_x <= x {
-                        continue;
-                    }
-                    if let Some(obj_pixel) = self.get_object_pixel(idx, y, x) {
-                        let addr = 0xfe00 + idx * 4;
-                        let attr = self.memory.borrow().get_byte((addr + 3) as u16) as usize;
-                        if attr & (1 << 7) == 0 || matches!(bg_pixel, Color::White) {
-                            self.set_pixel(y, x, obj_pixel);
-                        }
-                        break;
-                    }
-                }
+            let row = reader.read_u8()? as usize;
+            if row >= 16 {
+                return Err(StateError::InvalidData("object row"));
             }
+            self.obj_idx.push((obj_x, idx, row));
+        }
+        self.window_line_counter = reader.read_usize()?;
+        self.wy_cond_triggered = reader.read_bool()?;
+        self.fetcher.load_state(reader)?;
+        let n = reader.read_u8()? as usize;
+        if n > 8 {
+            return Err(StateError::InvalidData("BG FIFO length"));
+        }
+        self.bg_fifo.clear();
+        for _ in 0..n {
+            self.bg_fifo.push_back(BgPixel {
+                color_id: reader.read_u8()? & 0x3,
+                palette: reader.read_u8()? & 0x7,
+                priority: reader.read_bool()?,
+            });
+        }
+        let n = reader.read_u8()? as usize;
+        if n > 8 {
+            return Err(StateError::InvalidData("OBJ FIFO length"));
+        }
+        self.obj_fifo.clear();
+        for _ in 0..n {
+            self.obj_fifo.push_back(ObjPixel {
+                color_id: reader.read_u8()? & 0x3,
+                palette: reader.read_u8()? & 0x7,
+                bg_priority: reader.read_bool()?,
+                oam_index: reader.read_u8()?,
+            });
+        }
+        self.lx = reader.read_usize()?;
+        if self.lx > DISPLAY_WIDTH {
+            return Err(StateError::InvalidData("PPU x coordinate"));
         }
-        if is_window_visible {
-            self.window_line_counter += 1;
+        self.discard_pixels = reader.read_usize()?;
+        self.first_fetch_done = reader.read_bool()?;
+        self.obj_fetch_dots = reader.read_usize()?;
+        self.mode_3_clocks = reader.read_usize()?;
+        if self.mode_3_clocks > 456 - 80 {
+            return Err(StateError::InvalidData("mode 3 length"));
+        }
+        self.window_rendered = reader.read_bool()?;
+        self.lcd_enabled = reader.read_bool()?;
+        self.blank_frame = reader.read_bool()?;
+        self.stat_line = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Fetcher {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let step = match self.step {
+            FetcherStep::GetTile => 0,
+            FetcherStep::GetTileDataLow => 1,
+            FetcherStep::GetTileDataHigh => 2,
+            FetcherStep::Push => 3,
+        };
+        writer.write_u8(step);
+        writer.write_usize(self.dots);
+        writer.write_usize(self.tile_x);
+        writer.write_bool(self.window);
+        writer.write_u8(self.tile_idx);
+        writer.write_u8(self.attr);
+        writer.write_u8(self.data_low);
+        writer.write_u8(self.data_high);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.step = match reader.read_u8()? {
+            0 => FetcherStep::GetTile,
+            1 => FetcherStep::GetTileDataLow,
+            2 => FetcherStep::GetTileDataHigh,
+            3 => FetcherStep::Push,
+            _ => return Err(StateError::InvalidData("fetcher step")),
+        };
+        self.dots = reader.read_usize()?;
+        self.tile_x = reader.read_usize()?;
+        self.window = reader.read_bool()?;
+        self.tile_idx = reader.read_u8()?;
+        self.attr = reader.read_u8()?;
+        self.data_low = reader.read_u8()?;
+        self.data_high = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oam_and_lcdc_changes_during_mode_3() {
+        // (LCDC, OAM Y, OAM attributes, address changed in mode 3, new value)
+        let cases = [
+            (0x93, 16, 0x00, 0xfe00, 100), // OAM DMA overwriting the Y coordinate
+            (0x97, 6, 0x40, 0xff40, 0x93), // 8x16 to 8x8 on a Y-flipped object
+        ];
+        for &(lcdc, obj_y, attr, address, value) in cases.iter() {
+            let memory = Rc::new(RefCell::new(Memory::new()));
+            {
+                let mut memory = memory.borrow_mut();
+                memory.lcd_control = lcdc;
+                memory.lcd_status = 0x82; // mode 2 of line 0
+                memory.obj_attr_memory[0..4].copy_from_slice(&[obj_y, 80, 0x01, attr]);
+            }
+            let mut ppu = PPU::new(Rc::clone(&memory));
+            ppu.clocks_to_finish = 80;
+            for _ in 0..81 {
+                ppu.tick();
+            }
+            assert_eq!(memory.borrow().lcd_status & 0x3, 3);
+            match address {
+                0xfe00 => memory.borrow_mut().obj_attr_memory[0] = value,
+                _ => memory.borrow_mut().lcd_control = value,
+            }
+            for _ in 81..456 {
+                ppu.tick();
+            }
+            assert_eq!(memory.borrow().ly, 1, "address {:#06x}", address);
         }
     }
 }
@@ -1,51 +1,68 @@
 use crate::memory::Memory;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 
-// TIMA is incremented at the clock frequency specified by the TAC register.
-// DIV is incremented at a rate of 16384 Hz (= CPU Clock / 256)
+// DIV is the upper 8 bits of a 16-bit system counter incremented every clock.
+// TIMA is incremented on the falling edge of the counter bit selected by TAC,
+// ANDed with the timer enable bit, so resetting DIV or changing TAC can also increment it.
+// see https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
 pub struct Timer {
     pub memory: Rc<RefCell<Memory>>,
-    pub timer_counter: usize,
-    pub divider_counter: usize,
+    pub last_signal: bool,
 }
 
 impl Timer {
     pub fn new(memory: Rc<RefCell<Memory>>) -> Timer {
         Timer {
             memory,
-            timer_counter: 0,
-            divider_counter: 0,
+            last_signal: false,
         }
     }
+
     pub fn tick(&mut self) {
         let mut memory = self.memory.borrow_mut();
-        self.divider_counter += 1;
-        if self.divider_counter == 256 {
-            let value = memory.divider.wrapping_add(1);
-            memory.divider = value;
-            self.divider_counter = 0;
+        if memory.timer_reload_clocks > 0 {
+            memory.timer_reload_clocks -= 1;
         }
-        if memory.timer_control & (1 << 2) != 0 {
-            let mode = memory.timer_control & 3;
-            let target = match mode {
-                0 => 1024, // 4096 Hz (= CPU Clock / 1024)
-                1 => 16,   // 262144 Hz (= CPU Clock / 16)
-                2 => 64,   // 65536 Hz (= CPU Clock / 64)
-                3 => 256,  // 16384 Hz (=CPU Clock / 256)
-                _ => unreachable!(),
-            };
-            self.timer_counter += 1;
-            if self.timer_counter == target {
-                let (value, overflow) = memory.timer.overflowing_add(1);
-                if overflow {
-                    memory.timer = memory.timer_modulo;
-                    memory.interrupt_flag |= 1 << 2;
-                } else {
-                    memory.timer = value;
-                }
-                self.timer_counter = 0;
+        // TIMA reads 0x00 for one M-cycle after an overflow before it is reloaded.
+        if memory.timer_overflow_clocks > 0 {
+            memory.timer_overflow_clocks -= 1;
+            if memory.timer_overflow_clocks == 0 {
+                memory.timer = memory.timer_modulo;
+                memory.interrupt_flag |= 1 << 2;
+                memory.timer_reload_clocks = 4;
             }
         }
+
+        memory.system_counter = memory.system_counter.wrapping_add(1);
+        let bit = match memory.timer_control & 3 {
+            0 => 9, // 4096 Hz (= CPU Clock / 1024)
+            1 => 3, // 262144 Hz (= CPU Clock / 16)
+            2 => 5, // 65536 Hz (= CPU Clock / 64)
+            3 => 7, // 16384 Hz (= CPU Clock / 256)
+            _ => unreachable!(),
+        };
+        let signal =
+            memory.timer_control & (1 << 2) != 0 && memory.system_counter & (1 << bit) != 0;
+        if self.last_signal && !signal {
+            let (value, overflow) = memory.timer.overflowing_add(1);
+            memory.timer = value;
+            if overflow {
+                memory.timer_overflow_clocks = 4;
+            }
+        }
+        self.last_signal = signal;
+    }
+}
+
+impl Snapshot for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.last_signal);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.last_signal = reader.read_bool()?;
+        Ok(())
     }
 }
@@ -0,0 +1,95 @@
+// RIFF WAVE encoder for 16-bit PCM.
+// see http://soundfile.sapp.org/doc/WaveFormat/
+
+const HEADER_SIZE: usize = 44;
+
+pub fn encode(samples: &[i16], sample_rate: u32, channels: u16) -> Vec<u8> {
+    let data_size = (samples.len() * 2) as u32;
+    let block_align = channels * 2;
+    let byte_rate = sample_rate * block_align as u32;
+    let mut wav = Vec::with_capacity(HEADER_SIZE + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    // fmt chunk
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&byte_rate.to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+
+    // data chunk
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+pub fn to_pcm16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(wav: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([wav[offset], wav[offset + 1]])
+    }
+
+    fn u32_at(wav: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_fields() {
+        // (samples, sample rate, channels, block align, byte rate)
+        let cases = [
+            (0, 48000, 2, 4, 192000),
+            (6, 44100, 2, 4, 176400),
+            (5, 32000, 1, 2, 64000),
+        ];
+        for &(n, sample_rate, channels, block_align, byte_rate) in cases.iter() {
+            let samples: Vec<i16> = (0..n).map(|i| i as i16 - 2).collect();
+            let wav = encode(&samples, sample_rate, channels);
+            let data_size = n as u32 * 2;
+            assert_eq!(wav.len(), HEADER_SIZE + data_size as usize);
+            assert_eq!(&wav[0..4], b"RIFF");
+            assert_eq!(u32_at(&wav, 4), 36 + data_size);
+            assert_eq!(&wav[8..16], b"WAVEfmt ");
+            assert_eq!(u32_at(&wav, 16), 16);
+            assert_eq!(u16_at(&wav, 20), 1);
+            assert_eq!(u16_at(&wav, 22), channels);
+            assert_eq!(u32_at(&wav, 24), sample_rate);
+            assert_eq!(u32_at(&wav, 28), byte_rate);
+            assert_eq!(u16_at(&wav, 32), block_align);
+            assert_eq!(u16_at(&wav, 34), 16);
+            assert_eq!(&wav[36..40], b"data");
+            assert_eq!(u32_at(&wav, 40), data_size);
+            for (i, sample) in samples.iter().enumerate() {
+                assert_eq!(u16_at(&wav, HEADER_SIZE + i * 2) as i16, *sample);
+            }
+        }
+    }
+
+    #[test]
+    fn pcm16_conversion() {
+        // (sample, expected)
+        let cases = [
+            (0.0, 0),
+            (1.0, 32767),
+            (-1.0, -32767),
+            (2.0, 32767),
+            (-3.0, -32767),
+        ];
+        for &(sample, expected) in cases.iter() {
+            assert_eq!(to_pcm16(sample), expected, "{}", sample);
+        }
+    }
+}
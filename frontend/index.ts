@@ -153,13 +153,20 @@ const romInputChangeHandler = async () => {
     const buf = await romFile.arrayBuffer();
     const romData = new Uint8Array(buf);
     emulator.init();
-    emulator.load_rom(romData);
+    try {
+        emulator.load_rom(romData);
+    } catch (e) {
+        alert(`Failed to load ROM: ${(e as Error).message}`);
+        return;
+    }
     emulator.run();
-    audioCtx = new AudioContext({ sampleRate: 4194304 / 87 });
+    audioCtx = new AudioContext();
+    emulator.set_sample_rate(audioCtx.sampleRate);
     await audioCtx.audioWorklet.addModule(workletUrl);
     ringBufferNode = new AudioWorkletNode(
         audioCtx,
         "ring-buffer-worklet-processor",
+        { outputChannelCount: [emulator.get_audio_channels()] },
     );
     ringBufferNode.connect(audioCtx.destination);
     requestAnimationFrame(nextFrame);
@@ -175,7 +182,11 @@ const savedataInputChangeHandler = async () => {
     const savedataFile = savedataInput.files[0];
     const buf = await savedataFile.arrayBuffer();
     const savedata = new Uint8Array(buf);
-    emulator.load_savedata(savedata);
+    try {
+        emulator.load_savedata(savedata);
+    } catch (e) {
+        alert(`Failed to load save data: ${(e as Error).message}`);
+    }
 }
 
 const exportSavedataHandler = () => {
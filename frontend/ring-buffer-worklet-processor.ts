@@ -11,9 +11,14 @@ class RingBufferWorkletProcessor extends AudioWorkletProcessor {
     }
 
     process(input: Float32Array[][], outputs: Float32Array[][], parameters: Record<string, Float32Array>): boolean {
-        const output = outputs[0];
-        const channel = output[0];
-        this.ringBuffer.pop(channel);
+        // the emulator produces interleaved stereo frames.
+        const [left, right] = outputs[0];
+        const frames = new Float32Array(left.length * 2);
+        this.ringBuffer.pop(frames);
+        for (let i = 0; i < left.length; i++) {
+            left[i] = frames[2 * i];
+            right[i] = frames[2 * i + 1];
+        }
         return true;
     }
 }
@@ -18,8 +18,8 @@ class RingBuffer {
 
     pop(output: Float32Array) {
         const size = this.size();
-        if (size < 128) return;
-        for (let i = 0; i < 128; i++) {
+        if (size < output.length) return;
+        for (let i = 0; i < output.length; i++) {
             output[i] = this.buffer[this.readPos];
             this.readPos = (this.readPos + 1) % this.buffer.length;
         }